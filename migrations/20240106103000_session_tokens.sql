@@ -0,0 +1,7 @@
+-- Sessions used to be identified by their autoincrement id, which is guessable.
+-- Old sessions can't be upgraded since the client never had a token, so drop them.
+DELETE FROM sessions;
+
+ALTER TABLE sessions ADD COLUMN token_hash TEXT;
+
+CREATE UNIQUE INDEX sessions_token_hash ON sessions(token_hash);
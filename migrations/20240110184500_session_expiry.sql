@@ -0,0 +1,15 @@
+-- Timestamps are unix seconds.
+-- `expires_at` is the absolute deadline, `idle_timeout` is how long a session
+-- may go unused, counted from `last_seen_at`.
+ALTER TABLE sessions ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE sessions ADD COLUMN last_seen_at INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE sessions ADD COLUMN expires_at INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE sessions ADD COLUMN idle_timeout INTEGER NOT NULL DEFAULT 0;
+
+UPDATE sessions
+SET created_at = unixepoch(),
+    last_seen_at = unixepoch(),
+    expires_at = unixepoch() + 86400,
+    idle_timeout = 7200;
+
+CREATE INDEX sessions_expires_at ON sessions(expires_at);
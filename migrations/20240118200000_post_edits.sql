@@ -0,0 +1,2 @@
+-- Unix seconds of the last edit, NULL for posts that were never edited
+ALTER TABLE posts ADD COLUMN edited_at INTEGER;
@@ -0,0 +1,11 @@
+-- Unix seconds. Rows from before this migration get the time it ran.
+ALTER TABLE posts ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE comments ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE likes ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+
+UPDATE posts SET created_at = unixepoch();
+UPDATE comments SET created_at = unixepoch();
+UPDATE likes SET created_at = unixepoch();
+
+CREATE INDEX posts_created_at ON posts(created_at, id);
+CREATE INDEX comments_post_id ON comments(post_id, created_at);
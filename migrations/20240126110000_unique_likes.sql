@@ -0,0 +1,5 @@
+-- Keep the first like of every duplicate before enforcing uniqueness
+DELETE FROM likes
+WHERE id NOT IN (SELECT MIN(id) FROM likes GROUP BY user_id, post_id);
+
+CREATE UNIQUE INDEX likes_user_id_post_id ON likes(user_id, post_id);
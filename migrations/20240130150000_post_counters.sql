@@ -0,0 +1,24 @@
+-- Counters on posts so the feed doesn't aggregate likes and comments on every render.
+-- Kept current by the triggers below, existing rows are filled by the backfill migration.
+ALTER TABLE posts ADD COLUMN likes_count INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE posts ADD COLUMN comments_count INTEGER NOT NULL DEFAULT 0;
+
+CREATE TRIGGER likes_after_insert AFTER INSERT ON likes
+BEGIN
+  UPDATE posts SET likes_count = likes_count + 1 WHERE id = NEW.post_id;
+END;
+
+CREATE TRIGGER likes_after_delete AFTER DELETE ON likes
+BEGIN
+  UPDATE posts SET likes_count = likes_count - 1 WHERE id = OLD.post_id;
+END;
+
+CREATE TRIGGER comments_after_insert AFTER INSERT ON comments
+BEGIN
+  UPDATE posts SET comments_count = comments_count + 1 WHERE id = NEW.post_id;
+END;
+
+CREATE TRIGGER comments_after_delete AFTER DELETE ON comments
+BEGIN
+  UPDATE posts SET comments_count = comments_count - 1 WHERE id = OLD.post_id;
+END;
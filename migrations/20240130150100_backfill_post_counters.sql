@@ -0,0 +1,3 @@
+UPDATE posts
+SET likes_count = (SELECT COUNT(*) FROM likes WHERE likes.post_id = posts.id),
+    comments_count = (SELECT COUNT(*) FROM comments WHERE comments.post_id = posts.id);
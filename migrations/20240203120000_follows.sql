@@ -0,0 +1,11 @@
+CREATE TABLE follows (
+  follower_id INTEGER NOT NULL,
+  followee_id INTEGER NOT NULL,
+  created_at INTEGER NOT NULL,
+  PRIMARY KEY (follower_id, followee_id),
+  FOREIGN KEY (follower_id) REFERENCES users(id),
+  FOREIGN KEY (followee_id) REFERENCES users(id)
+);
+
+CREATE INDEX follows_followee_id ON follows(followee_id);
+CREATE INDEX posts_author_id ON posts(author_id, created_at, id);
@@ -0,0 +1,5 @@
+-- Unix seconds. Users from before this migration get the time it ran.
+ALTER TABLE users ADD COLUMN bio TEXT NOT NULL DEFAULT '';
+ALTER TABLE users ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+
+UPDATE users SET created_at = unixepoch();
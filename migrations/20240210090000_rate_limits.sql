@@ -0,0 +1,7 @@
+-- Attempt counters keyed by what they limit, e.g. `login-ip:127.0.0.1`
+CREATE TABLE rate_limits (
+  key TEXT PRIMARY KEY NOT NULL,
+  attempts INTEGER NOT NULL,
+  last_attempt_at INTEGER NOT NULL,
+  locked_until INTEGER NOT NULL DEFAULT 0
+);
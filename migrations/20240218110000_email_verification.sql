@@ -0,0 +1,5 @@
+-- Unix seconds, NULL until the user opens the mailed link.
+-- Accounts from before verification existed are trusted as they are.
+ALTER TABLE users ADD COLUMN email_verified_at INTEGER;
+
+UPDATE users SET email_verified_at = unixepoch();
@@ -0,0 +1,4 @@
+-- 'user', 'moderator' or 'admin'. The first admin is promoted from ADMIN_EMAIL at startup.
+ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'user';
+-- Unix seconds, suspended accounts can't log in
+ALTER TABLE users ADD COLUMN suspended_at INTEGER;
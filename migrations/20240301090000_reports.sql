@@ -0,0 +1,39 @@
+-- Unix seconds, hidden content is only shown to moderators
+ALTER TABLE posts ADD COLUMN hidden_at INTEGER;
+ALTER TABLE comments ADD COLUMN hidden_at INTEGER;
+
+-- Either post_id or comment_id is set. Reports go away with the content they are about.
+CREATE TABLE reports (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  reporter_id INTEGER NOT NULL,
+  post_id INTEGER,
+  comment_id INTEGER,
+  reason TEXT NOT NULL,
+  created_at INTEGER NOT NULL,
+  resolved_at INTEGER,
+  FOREIGN KEY (reporter_id) REFERENCES users(id),
+  FOREIGN KEY (post_id) REFERENCES posts(id) ON DELETE CASCADE,
+  FOREIGN KEY (comment_id) REFERENCES comments(id) ON DELETE CASCADE
+);
+
+-- Reporting the same content twice keeps the first report
+CREATE UNIQUE INDEX reports_reporter_post ON reports(reporter_id, post_id);
+CREATE UNIQUE INDEX reports_reporter_comment ON reports(reporter_id, comment_id);
+CREATE INDEX reports_resolved_at ON reports(resolved_at);
+
+-- Outcome of every resolved report. No foreign keys on the content,
+-- entries outlive it and keep a copy of what it said.
+CREATE TABLE audit_log (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  moderator_id INTEGER NOT NULL,
+  outcome TEXT NOT NULL,
+  report_id INTEGER NOT NULL,
+  author_id INTEGER NOT NULL,
+  post_id INTEGER,
+  comment_id INTEGER,
+  content TEXT NOT NULL,
+  reason TEXT NOT NULL,
+  created_at INTEGER NOT NULL,
+  FOREIGN KEY (moderator_id) REFERENCES users(id),
+  FOREIGN KEY (author_id) REFERENCES users(id)
+);
@@ -0,0 +1,31 @@
+-- comments_count only counts comments that aren't hidden
+DROP TRIGGER comments_after_insert;
+DROP TRIGGER comments_after_delete;
+
+CREATE TRIGGER comments_after_insert AFTER INSERT ON comments
+WHEN NEW.hidden_at IS NULL
+BEGIN
+  UPDATE posts SET comments_count = comments_count + 1 WHERE id = NEW.post_id;
+END;
+
+CREATE TRIGGER comments_after_delete AFTER DELETE ON comments
+WHEN OLD.hidden_at IS NULL
+BEGIN
+  UPDATE posts SET comments_count = comments_count - 1 WHERE id = OLD.post_id;
+END;
+
+CREATE TRIGGER comments_after_hide AFTER UPDATE OF hidden_at ON comments
+WHEN OLD.hidden_at IS NULL AND NEW.hidden_at IS NOT NULL
+BEGIN
+  UPDATE posts SET comments_count = comments_count - 1 WHERE id = NEW.post_id;
+END;
+
+CREATE TRIGGER comments_after_unhide AFTER UPDATE OF hidden_at ON comments
+WHEN OLD.hidden_at IS NOT NULL AND NEW.hidden_at IS NULL
+BEGIN
+  UPDATE posts SET comments_count = comments_count + 1 WHERE id = NEW.post_id;
+END;
+
+UPDATE posts SET comments_count = (
+  SELECT COUNT(*) FROM comments c WHERE c.post_id = posts.id AND c.hidden_at IS NULL
+);
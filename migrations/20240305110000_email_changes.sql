@@ -0,0 +1,3 @@
+-- Unix seconds of the last email change, NULL while the account has the address it registered with.
+-- Registrations that never confirm their address give it up to the next account claiming it.
+ALTER TABLE users ADD COLUMN email_changed_at INTEGER;
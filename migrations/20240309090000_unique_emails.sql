@@ -0,0 +1,18 @@
+-- Emails are looked up in any case, so they have to be unique in any case.
+-- Where several accounts share an address, the first confirmed one keeps it, else the oldest.
+-- The others get a placeholder that nobody can log in or reset a password with.
+UPDATE users SET email = 'duplicate-' || id || '.' || email, email_verified_at = NULL
+WHERE EXISTS (
+  SELECT 1 FROM users other
+  WHERE lower(other.email) = lower(users.email)
+    AND other.id <> users.id
+    AND (
+      (other.email_verified_at IS NOT NULL) > (users.email_verified_at IS NOT NULL)
+      OR (
+        (other.email_verified_at IS NOT NULL) = (users.email_verified_at IS NOT NULL)
+        AND other.id < users.id
+      )
+    )
+);
+
+CREATE UNIQUE INDEX users_email ON users(lower(email));
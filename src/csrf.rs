@@ -0,0 +1,110 @@
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request},
+    http::{request::Parts, HeaderMap, Method},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::{
+    cookie::{Cookie, SameSite},
+    CookieJar,
+};
+
+use crate::{
+    error::AppError,
+    helpers::{app_origin, cookie_secure, generate_token},
+    password::constant_time_eq,
+};
+
+pub const CSRF_COOKIE_KEY: &str = "csrf";
+/// Pages send the token back in this header through `hx-headers` on `<body>`
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+/// Token of the current request, to be rendered into pages
+#[derive(Clone)]
+pub struct CsrfToken(pub String);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for CsrfToken {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<CsrfToken>()
+            .cloned()
+            .ok_or_else(|| AppError::Internal(anyhow::anyhow!("CSRF middleware is missing")))
+    }
+}
+
+fn csrf_cookie(token: String) -> Cookie<'static> {
+    Cookie::build((CSRF_COOKIE_KEY, token))
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .secure(cookie_secure())
+        .build()
+}
+
+/// Why an unsafe request doesn't look like it came from one of our pages
+fn rejection_reason(headers: &HeaderMap, cookie_token: Option<&str>) -> Option<&'static str> {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+
+    // Browsers send it on every request, `none` means the user did it directly
+    if let Some(site) = header("Sec-Fetch-Site") {
+        if site != "same-origin" && site != "none" {
+            return Some("cross site request");
+        }
+    }
+
+    // The Host header is up to the client, `APP_URL` is what we are actually served as
+    if let Some(origin) = header("Origin") {
+        if !origin.eq_ignore_ascii_case(&app_origin()) {
+            return Some("origin doesn't match APP_URL");
+        }
+    }
+
+    match (cookie_token, header(CSRF_HEADER)) {
+        (Some(cookie_token), Some(header_token))
+            if constant_time_eq(cookie_token.as_bytes(), header_token.as_bytes()) =>
+        {
+            None
+        }
+        _ => Some("token mismatch"),
+    }
+}
+
+/// Double submit cookie middleware.
+/// Hands out a token cookie, and rejects state changing requests with 403
+/// unless they echo it in `X-CSRF-Token` and come from our own origin.
+pub async fn protect(mut request: Request, next: Next) -> Response {
+    let jar = CookieJar::from_headers(request.headers());
+    let cookie_token = jar
+        .get(CSRF_COOKIE_KEY)
+        .map(|cookie| cookie.value().to_owned())
+        .filter(|token| !token.is_empty());
+
+    let unsafe_method = !matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    );
+    if unsafe_method {
+        if let Some(reason) = rejection_reason(request.headers(), cookie_token.as_deref()) {
+            tracing::debug!(method = %request.method(), uri = %request.uri(), "CSRF check failed: {reason}");
+            return AppError::Forbidden.into_response();
+        }
+    }
+
+    match cookie_token {
+        Some(token) => {
+            request.extensions_mut().insert(CsrfToken(token));
+            next.run(request).await
+        }
+        None => {
+            let token = generate_token();
+            request.extensions_mut().insert(CsrfToken(token.clone()));
+            let response = next.run(request).await;
+            (jar.add(csrf_cookie(token)), response).into_response()
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use anyhow::Result;
+use sqlx::{FromRow, SqliteConnection, SqlitePool};
+
+use super::Role;
+use crate::timestamp::Timestamp;
+
+/// Most rows a dashboard search returns, newest first
+pub const SEARCH_LIMIT: i64 = 50;
+
+#[derive(FromRow, Debug)]
+pub struct Account {
+    pub id: i32,
+    pub name: String,
+    pub email: String,
+    pub role: Role,
+    pub email_verified_at: Option<Timestamp>,
+    pub suspended_at: Option<Timestamp>,
+    pub created_at: Timestamp,
+    pub posts_count: i32,
+}
+
+const ACCOUNT_COLUMNS: &str = "
+    u.id,
+    u.name,
+    u.email,
+    u.role,
+    u.email_verified_at,
+    u.suspended_at,
+    u.created_at,
+    (SELECT COUNT(*) FROM posts p WHERE p.author_id = u.id) AS posts_count
+";
+
+/// Case insensitive match on name or email, an empty `search` matches everyone
+pub async fn search_accounts(connection_pool: &SqlitePool, search: &str) -> Result<Vec<Account>> {
+    let query = format!(
+        "
+SELECT {ACCOUNT_COLUMNS}
+FROM users u
+WHERE instr(lower(u.name), lower($1)) > 0 OR instr(lower(u.email), lower($1)) > 0
+ORDER BY u.id DESC
+LIMIT $2
+"
+    );
+
+    Ok(sqlx::query_as::<_, Account>(&query)
+        .bind(search)
+        .bind(SEARCH_LIMIT)
+        .fetch_all(connection_pool)
+        .await?)
+}
+
+pub async fn get_account(connection_pool: &SqlitePool, user_id: i32) -> Result<Option<Account>> {
+    let query = format!("SELECT {ACCOUNT_COLUMNS} FROM users u WHERE u.id = $1");
+
+    Ok(sqlx::query_as::<_, Account>(&query)
+        .bind(user_id)
+        .fetch_optional(connection_pool)
+        .await?)
+}
+
+pub async fn set_role(connection_pool: &SqlitePool, user_id: i32, role: Role) -> Result<()> {
+    sqlx::query("update users set role = $1 where id = $2")
+        .bind(role)
+        .bind(user_id)
+        .execute(connection_pool)
+        .await?;
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Promotion {
+    Promoted,
+    /// An account has the address but never confirmed it, so it may not own it
+    Unverified,
+    NoAccount,
+}
+
+/// Make the account with the confirmed address `email` an admin
+pub async fn promote_to_admin(connection_pool: &SqlitePool, email: &str) -> Result<Promotion> {
+    let result = sqlx::query(
+        "update users set role = 'admin' where lower(email) = lower($1) and email_verified_at is not null",
+    )
+    .bind(email)
+    .execute(connection_pool)
+    .await?;
+    if result.rows_affected() > 0 {
+        return Ok(Promotion::Promoted);
+    }
+
+    let unverified = sqlx::query("select id from users where lower(email) = lower($1)")
+        .bind(email)
+        .fetch_optional(connection_pool)
+        .await?
+        .is_some();
+
+    Ok(if unverified {
+        Promotion::Unverified
+    } else {
+        Promotion::NoAccount
+    })
+}
+
+/// Logs the user out everywhere, including logins waiting for a second factor
+pub async fn suspend(connection_pool: &SqlitePool, user_id: i32) -> Result<()> {
+    let mut transaction = connection_pool.begin().await?;
+    suspend_in(&mut transaction, user_id).await?;
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+/// `suspend` as part of a larger transaction
+pub async fn suspend_in(connection: &mut SqliteConnection, user_id: i32) -> Result<()> {
+    sqlx::query(
+        "update users set suspended_at = unixepoch() where id = $1 and suspended_at is null",
+    )
+    .bind(user_id)
+    .execute(&mut *connection)
+    .await?;
+    sqlx::query("delete from sessions where user_id = $1")
+        .bind(user_id)
+        .execute(&mut *connection)
+        .await?;
+    sqlx::query("delete from login_challenges where user_id = $1")
+        .bind(user_id)
+        .execute(&mut *connection)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn unsuspend(connection_pool: &SqlitePool, user_id: i32) -> Result<()> {
+    sqlx::query("update users set suspended_at = null where id = $1")
+        .bind(user_id)
+        .execute(connection_pool)
+        .await?;
+
+    Ok(())
+}
+
+#[derive(FromRow, Debug)]
+pub struct PostSummary {
+    pub id: i32,
+    pub body: String,
+    pub author: String,
+    pub author_id: i32,
+    pub created_at: Timestamp,
+    pub likes_count: i32,
+    pub comments_count: i32,
+}
+
+/// Case insensitive match on the body or the author's name
+pub async fn search_posts(connection_pool: &SqlitePool, search: &str) -> Result<Vec<PostSummary>> {
+    let query = "
+SELECT p.id, p.body, u.name AS author, p.author_id, p.created_at, p.likes_count, p.comments_count
+FROM posts p
+JOIN users u ON u.id = p.author_id
+WHERE instr(lower(p.body), lower($1)) > 0 OR instr(lower(u.name), lower($1)) > 0
+ORDER BY p.id DESC
+LIMIT $2
+";
+
+    Ok(sqlx::query_as::<_, PostSummary>(query)
+        .bind(search)
+        .bind(SEARCH_LIMIT)
+        .fetch_all(connection_pool)
+        .await?)
+}
+
+#[derive(FromRow, Debug)]
+pub struct ActiveSession {
+    pub id: i32,
+    pub user_id: i32,
+    pub user_name: String,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: Timestamp,
+    pub last_seen_at: Timestamp,
+}
+
+/// Live sessions of every user, matched on the user's name or email, the IP address or the device
+pub async fn search_sessions(
+    connection_pool: &SqlitePool,
+    search: &str,
+) -> Result<Vec<ActiveSession>> {
+    let query = "
+SELECT s.id, s.user_id, u.name AS user_name, s.user_agent, s.ip_address, s.created_at, s.last_seen_at
+FROM sessions s
+JOIN users u ON u.id = s.user_id
+WHERE s.expires_at > unixepoch()
+  AND s.last_seen_at + s.idle_timeout > unixepoch()
+  AND (
+    instr(lower(u.name), lower($1)) > 0
+    OR instr(lower(u.email), lower($1)) > 0
+    OR instr(coalesce(s.ip_address, ''), $1) > 0
+    OR instr(lower(coalesce(s.user_agent, '')), lower($1)) > 0
+  )
+ORDER BY s.last_seen_at DESC
+LIMIT $2
+";
+
+    Ok(sqlx::query_as::<_, ActiveSession>(query)
+        .bind(search)
+        .bind(SEARCH_LIMIT)
+        .fetch_all(connection_pool)
+        .await?)
+}
+
+pub async fn delete_session(connection_pool: &SqlitePool, session_id: i32) -> Result<()> {
+    sqlx::query("delete from sessions where id = $1")
+        .bind(session_id)
+        .execute(connection_pool)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{
+        create_session, create_user, get_user_from_session, test_pool, DEFAULT_SESSION_LIFETIME,
+    };
+
+    #[tokio::test]
+    async fn search_ignores_case() {
+        let connection_pool = test_pool().await;
+
+        let accounts = search_accounts(&connection_pool, "SOLO").await.unwrap();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].name, "Solomon");
+        assert_eq!(accounts[0].role, Role::User);
+
+        let everyone = search_accounts(&connection_pool, "").await.unwrap();
+        assert_eq!(everyone.len(), 2);
+
+        let posts = search_posts(&connection_pool, "music").await.unwrap();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].author, "Solomon");
+    }
+
+    #[tokio::test]
+    async fn suspension_ends_sessions_until_lifted() {
+        let connection_pool = test_pool().await;
+        let token = create_session(&connection_pool, 2, &DEFAULT_SESSION_LIFETIME, None, None)
+            .await
+            .unwrap();
+
+        suspend(&connection_pool, 2).await.unwrap();
+        assert!(get_user_from_session(&connection_pool, &token)
+            .await
+            .unwrap()
+            .is_none());
+        let account = get_account(&connection_pool, 2).await.unwrap().unwrap();
+        assert!(account.suspended_at.is_some());
+
+        // A session created anyway isn't honoured while suspended
+        let token = create_session(&connection_pool, 2, &DEFAULT_SESSION_LIFETIME, None, None)
+            .await
+            .unwrap();
+        assert!(get_user_from_session(&connection_pool, &token)
+            .await
+            .unwrap()
+            .is_none());
+
+        unsuspend(&connection_pool, 2).await.unwrap();
+        let user = get_user_from_session(&connection_pool, &token)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.id, 2);
+    }
+
+    #[tokio::test]
+    async fn nobody_is_an_admin_until_promoted() {
+        let connection_pool = test_pool().await;
+        let everyone = search_accounts(&connection_pool, "").await.unwrap();
+        assert!(everyone.iter().all(|account| account.role == Role::User));
+
+        assert_eq!(
+            promote_to_admin(&connection_pool, "nobody@tempo.com")
+                .await
+                .unwrap(),
+            Promotion::NoAccount
+        );
+        assert_eq!(
+            promote_to_admin(&connection_pool, "Tempo@Tempo.com")
+                .await
+                .unwrap(),
+            Promotion::Promoted
+        );
+        let account = get_account(&connection_pool, 1).await.unwrap().unwrap();
+        assert_eq!(account.role, Role::Admin);
+    }
+
+    #[tokio::test]
+    async fn unconfirmed_addresses_are_not_promoted() {
+        let connection_pool = test_pool().await;
+        let user_id = create_user(&connection_pool, "admin@tempo.com", "Squatter", "hash")
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            promote_to_admin(&connection_pool, "admin@tempo.com")
+                .await
+                .unwrap(),
+            Promotion::Unverified
+        );
+        let account = get_account(&connection_pool, user_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(account.role, Role::User);
+    }
+}
@@ -0,0 +1,33 @@
+use anyhow::Result;
+use sqlx::SqlitePool;
+
+/// Idempotent, following someone twice is a no-op
+pub async fn follow(
+    connection_pool: &SqlitePool,
+    follower_id: i32,
+    followee_id: i32,
+) -> Result<()> {
+    sqlx::query(
+        "insert or ignore into follows (follower_id, followee_id, created_at) values ($1, $2, unixepoch())",
+    )
+    .bind(follower_id)
+    .bind(followee_id)
+    .execute(connection_pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn unfollow(
+    connection_pool: &SqlitePool,
+    follower_id: i32,
+    followee_id: i32,
+) -> Result<()> {
+    sqlx::query("delete from follows where follower_id = $1 and followee_id = $2")
+        .bind(follower_id)
+        .bind(followee_id)
+        .execute(connection_pool)
+        .await?;
+
+    Ok(())
+}
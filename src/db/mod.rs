@@ -1,15 +1,72 @@
-use std::str::FromStr;
+//! Queries, grouped by feature in the submodules.
+//!
+//! Transactions which both read and write start with a write. SQLite transactions begin deferred,
+//! and upgrading one that has read already fails with `SQLITE_BUSY` when another write got in between,
+//! instead of waiting for the lock like a transaction that wrote from the start does.
+
+use std::{fmt, str::FromStr};
+
+use serde::Deserialize;
 
 use anyhow::Result;
-use sqlx::{sqlite::SqliteConnectOptions, FromRow, Row, SqlitePool};
+use sqlx::{sqlite::SqliteConnectOptions, FromRow, Row, SqliteConnection, SqlitePool};
+
+use crate::{
+    helpers::{generate_token, hash_token},
+    timestamp::Timestamp,
+};
 
+pub mod admin;
+pub mod follows;
+pub mod password_resets;
 pub mod posts;
+pub mod rate_limits;
+pub mod reports;
+pub mod two_factor;
 
-#[derive(FromRow, Debug)]
+#[derive(FromRow, Debug, Clone)]
 pub struct User {
     pub id: i32,
     pub email: String,
     pub name: String,
+    pub email_verified_at: Option<Timestamp>,
+    pub role: Role,
+}
+
+impl User {
+    pub fn email_verified(&self) -> bool {
+        self.email_verified_at.is_some()
+    }
+
+    /// Moderators and admins see hidden content and work the report queue
+    pub fn is_moderator(&self) -> bool {
+        self.role >= Role::Moderator
+    }
+}
+
+/// Ordered by privilege, every role can do what the ones before it can
+#[derive(sqlx::Type, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Moderator,
+    Admin,
+}
+
+impl Role {
+    pub const ALL: [Role; 3] = [Role::User, Role::Moderator, Role::Admin];
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Role::User => "user",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        };
+        f.write_str(name)
+    }
 }
 
 pub async fn init() -> Result<SqlitePool> {
@@ -22,75 +79,501 @@ pub async fn init() -> Result<SqlitePool> {
     Ok(connection_pool)
 }
 
+/// Fresh in-memory database with all migrations applied
+#[cfg(test)]
+pub async fn test_pool() -> SqlitePool {
+    // Every connection to `:memory:` is its own database, so keep a single one
+    let connection_pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+
+    sqlx::migrate!().run(&connection_pool).await.unwrap();
+
+    connection_pool
+}
+
+/// Resolve the user of a live session and push its idle deadline forward
 pub async fn get_user_from_session(
     connection_pool: &SqlitePool,
-    session_id: i32,
+    session_token: &str,
 ) -> Result<Option<User>> {
-    Ok(sqlx::query_as::<_, User>(
-            "select u.id, u.name, u.email from users u join sessions s on u.id = s.user_id where s.id = $1"
+    let token_hash = hash_token(session_token);
+
+    let query = "
+select u.id, u.name, u.email, u.email_verified_at, u.role
+from users u
+join sessions s on u.id = s.user_id
+where s.token_hash = $1
+  and u.suspended_at is null
+  and s.expires_at > unixepoch()
+  and s.last_seen_at + s.idle_timeout > unixepoch()
+";
+    let user = sqlx::query_as::<_, User>(query)
+        .bind(&token_hash)
+        .fetch_optional(connection_pool)
+        .await?;
+
+    if user.is_some() {
+        // Sliding renewal, throttled so that not every request writes
+        sqlx::query(
+            "update sessions set last_seen_at = unixepoch() where token_hash = $1 and last_seen_at < unixepoch() - 60",
         )
-    .bind(session_id).fetch_optional(connection_pool).await?)
+        .bind(&token_hash)
+        .execute(connection_pool)
+        .await?;
+    }
+
+    Ok(user)
+}
+
+pub async fn get_user_by_id(connection_pool: &SqlitePool, user_id: i32) -> Result<Option<User>> {
+    Ok(sqlx::query_as::<_, User>(
+        "select id, name, email, email_verified_at, role from users where id = $1",
+    )
+    .bind(user_id)
+    .fetch_optional(connection_pool)
+    .await?)
 }
 
 #[derive(FromRow, Debug)]
-pub struct UserId {
+pub struct Profile {
     pub id: i32,
+    pub name: String,
+    pub bio: String,
+    pub created_at: Timestamp,
+    pub posts_count: i32,
+    pub followers_count: i32,
+    pub following_count: i32,
+    /// Whether the user the profile was fetched for follows this one
+    pub followed: bool,
 }
-pub async fn get_user_id_from_login(
+
+/// `viewer_id` to determine if the viewer follows the user
+pub async fn get_profile(
     connection_pool: &SqlitePool,
-    email: &str,
-    password: &str,
-) -> Result<Option<i32>> {
-    let result = sqlx::query_as::<_, UserId>("SELECT id FROM users WHERE email=$1 AND password=$2")
-        .bind(email)
-        .bind(password)
+    viewer_id: Option<i32>,
+    user_id: i32,
+) -> Result<Option<Profile>> {
+    let query = "
+SELECT
+    u.id,
+    u.name,
+    u.bio,
+    u.created_at,
+    (SELECT COUNT(*) FROM posts p WHERE p.author_id = u.id AND p.hidden_at IS NULL) AS posts_count,
+    (SELECT COUNT(*) FROM follows f WHERE f.followee_id = u.id) AS followers_count,
+    (SELECT COUNT(*) FROM follows f WHERE f.follower_id = u.id) AS following_count,
+    EXISTS (SELECT 1 FROM follows f WHERE f.follower_id = $1 AND f.followee_id = u.id) AS followed
+FROM
+    users u
+WHERE
+    u.id = $2;
+";
+
+    Ok(sqlx::query_as::<_, Profile>(query)
+        .bind(viewer_id.unwrap_or(0))
+        .bind(user_id)
         .fetch_optional(connection_pool)
+        .await?)
+}
+
+#[derive(FromRow, Debug)]
+pub struct Credentials {
+    pub id: i32,
+    /// Argon2 PHC string, or plaintext for rows which weren't migrated yet
+    pub password: String,
+    pub suspended: bool,
+}
+pub async fn get_credentials(
+    connection_pool: &SqlitePool,
+    email: &str,
+) -> Result<Option<Credentials>> {
+    Ok(sqlx::query_as::<_, Credentials>(
+        "SELECT id, password, suspended_at IS NOT NULL AS suspended FROM users WHERE lower(email) = lower($1)",
+    )
+    .bind(email)
+    .fetch_optional(connection_pool)
+    .await?)
+}
+
+pub async fn get_credentials_by_id(
+    connection_pool: &SqlitePool,
+    user_id: i32,
+) -> Result<Option<Credentials>> {
+    Ok(sqlx::query_as::<_, Credentials>(
+        "SELECT id, password, suspended_at IS NOT NULL AS suspended FROM users WHERE id = $1",
+    )
+    .bind(user_id)
+    .fetch_optional(connection_pool)
+    .await?)
+}
+
+pub async fn update_password_hash(
+    connection_pool: &SqlitePool,
+    user_id: i32,
+    password_hash: &str,
+) -> Result<()> {
+    sqlx::query("UPDATE users SET password = $1 WHERE id = $2")
+        .bind(password_hash)
+        .bind(user_id)
+        .execute(connection_pool)
         .await?;
+    Ok(())
+}
+
+pub async fn update_name(connection_pool: &SqlitePool, user_id: i32, name: &str) -> Result<()> {
+    sqlx::query("UPDATE users SET name = $1 WHERE id = $2")
+        .bind(name)
+        .bind(user_id)
+        .execute(connection_pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn update_bio(connection_pool: &SqlitePool, user_id: i32, bio: &str) -> Result<()> {
+    sqlx::query("UPDATE users SET bio = $1 WHERE id = $2")
+        .bind(bio)
+        .bind(user_id)
+        .execute(connection_pool)
+        .await?;
+    Ok(())
+}
+
+/// The new address has to be verified again.
+/// `false` when another account holds the address already.
+pub async fn update_email(connection_pool: &SqlitePool, user_id: i32, email: &str) -> Result<bool> {
+    let mut transaction = connection_pool.begin().await?;
+    release_stale_registration_in(&mut transaction, email).await?;
+
+    let result = sqlx::query(
+        "UPDATE users SET email = $1, email_verified_at = NULL, email_changed_at = unixepoch() WHERE id = $2",
+    )
+    .bind(email)
+    .bind(user_id)
+    .execute(&mut *transaction)
+    .await;
+    if is_unique_violation(&result) {
+        return Ok(false);
+    }
+    result?;
+
+    transaction.commit().await?;
+    Ok(true)
+}
 
-    Ok(match result {
-        Some(user) => Some(user.id),
-        None => None,
-    })
+/// Only verifies `email` if it is still the user's address
+pub async fn mark_email_verified(
+    connection_pool: &SqlitePool,
+    user_id: i32,
+    email: &str,
+) -> Result<()> {
+    sqlx::query(
+        "UPDATE users SET email_verified_at = unixepoch() WHERE id = $1 AND email = $2 AND email_verified_at IS NULL",
+    )
+    .bind(user_id)
+    .bind(email)
+    .execute(connection_pool)
+    .await?;
+    Ok(())
 }
 
+/// Seconds a new account holds its address without confirming it,
+/// so that nobody can keep others from registering with an address they don't own
+pub const UNCONFIRMED_REGISTRATION_HOLD: i64 = 7 * 24 * 60 * 60;
+
+/// Accounts which registered with `$1` long ago and never confirmed it.
+/// They can't have posted, commented or reported anything.
+const STALE_REGISTRATIONS: &str = "
+SELECT id FROM users
+WHERE lower(email) = lower($1)
+  AND email_verified_at IS NULL
+  AND email_changed_at IS NULL
+  AND role = 'user'
+  AND created_at <= unixepoch() - $2
+";
+
+/// Stale registrations don't count, the address goes to whoever claims it next
 pub async fn check_email_exists(connection_pool: &SqlitePool, email: &str) -> Result<bool> {
-    let result = sqlx::query_as::<_, UserId>("SELECT id FROM users WHERE email=$1")
+    let query = format!(
+        "SELECT id FROM users WHERE lower(email) = lower($1) AND id NOT IN ({STALE_REGISTRATIONS})"
+    );
+
+    let result = sqlx::query(&query)
         .bind(email)
+        .bind(UNCONFIRMED_REGISTRATION_HOLD)
         .fetch_optional(connection_pool)
         .await?;
     Ok(result.is_some())
 }
 
+/// Delete a stale registration of `email` along with what it left behind
+async fn release_stale_registration_in(
+    connection: &mut SqliteConnection,
+    email: &str,
+) -> Result<()> {
+    let references = [
+        ("sessions", "user_id"),
+        ("login_challenges", "user_id"),
+        ("recovery_codes", "user_id"),
+        ("password_resets", "user_id"),
+        ("likes", "user_id"),
+        ("follows", "follower_id"),
+        ("follows", "followee_id"),
+        ("users", "id"),
+    ];
+
+    for (table, column) in references {
+        let query = format!("DELETE FROM {table} WHERE {column} IN ({STALE_REGISTRATIONS})");
+        sqlx::query(&query)
+            .bind(email)
+            .bind(UNCONFIRMED_REGISTRATION_HOLD)
+            .execute(&mut *connection)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Emails are unique in any case, an account registering or changing to an address
+/// that was checked to be free can still lose the race for it
+fn is_unique_violation<T>(result: &sqlx::Result<T>) -> bool {
+    matches!(result, Err(sqlx::Error::Database(error)) if error.is_unique_violation())
+}
+
+/// Takes the address over from a stale registration.
+/// `None` when another account holds the address already.
 pub async fn create_user(
     connection_pool: &SqlitePool,
     email: &str,
     name: &str,
-    password: &str,
+    password_hash: &str,
+) -> Result<Option<i32>> {
+    let mut transaction = connection_pool.begin().await?;
+    release_stale_registration_in(&mut transaction, email).await?;
+
+    let result = sqlx::query(
+        "INSERT INTO users (email, name, password, created_at) VALUES ($1, $2, $3, unixepoch()) RETURNING id",
+    )
+    .bind(email)
+    .bind(name)
+    .bind(password_hash)
+    .fetch_one(&mut *transaction)
+    .await;
+    if is_unique_violation(&result) {
+        return Ok(None);
+    }
+    let user_id = result?.get(0);
+
+    transaction.commit().await?;
+    Ok(Some(user_id))
+}
+
+pub struct SessionLifetime {
+    /// Seconds a session may stay unused
+    pub idle: i64,
+    /// Seconds a session lives at most, regardless of activity
+    pub absolute: i64,
+}
+
+pub const DEFAULT_SESSION_LIFETIME: SessionLifetime = SessionLifetime {
+    idle: 2 * 60 * 60,
+    absolute: 24 * 60 * 60,
+};
+
+/// Used when "remember me" is checked on login
+pub const REMEMBERED_SESSION_LIFETIME: SessionLifetime = SessionLifetime {
+    idle: 30 * 24 * 60 * 60,
+    absolute: 30 * 24 * 60 * 60,
+};
+
+/// Returns the session token, only its hash is stored
+pub async fn create_session(
+    connection_pool: &SqlitePool,
+    user_id: i32,
+    lifetime: &SessionLifetime,
+    user_agent: Option<&str>,
+    ip_address: Option<&str>,
+) -> Result<String> {
+    let token = generate_token();
+
+    sqlx::query(
+        "INSERT INTO sessions (user_id, token_hash, created_at, last_seen_at, expires_at, idle_timeout, user_agent, ip_address)
+         VALUES ($1, $2, unixepoch(), unixepoch(), unixepoch() + $3, $4, $5, $6)",
+    )
+    .bind(user_id)
+    .bind(hash_token(&token))
+    .bind(lifetime.absolute)
+    .bind(lifetime.idle)
+    .bind(user_agent)
+    .bind(ip_address)
+    .execute(connection_pool)
+    .await?;
+
+    Ok(token)
+}
+
+#[derive(FromRow, Debug)]
+pub struct Session {
+    pub id: i32,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: Timestamp,
+    pub last_seen_at: Timestamp,
+    /// Whether this is the session the list was requested with
+    pub current: bool,
+}
+
+/// Live sessions of a user, most recently used first
+pub async fn get_user_sessions(
+    connection_pool: &SqlitePool,
+    user_id: i32,
+    current_session_token: &str,
+) -> Result<Vec<Session>> {
+    let query = "
+select id, user_agent, ip_address, created_at, last_seen_at, token_hash = $2 as current
+from sessions
+where user_id = $1
+  and expires_at > unixepoch()
+  and last_seen_at + idle_timeout > unixepoch()
+order by last_seen_at desc
+";
+
+    Ok(sqlx::query_as::<_, Session>(query)
+        .bind(user_id)
+        .bind(hash_token(current_session_token))
+        .fetch_all(connection_pool)
+        .await?)
+}
+
+/// Only deletes the session if it belongs to `user_id`
+pub async fn delete_user_session(
+    connection_pool: &SqlitePool,
+    user_id: i32,
+    session_id: i32,
 ) -> Result<()> {
-    sqlx::query("INSERT INTO users (email, name, password) VALUES ($1, $2, $3)")
-        .bind(email)
-        .bind(name)
-        .bind(password)
+    sqlx::query("delete from sessions where id = $1 and user_id = $2")
+        .bind(session_id)
+        .bind(user_id)
         .execute(connection_pool)
         .await?;
+
     Ok(())
 }
 
-pub async fn create_session(connection_pool: &SqlitePool, user_id: i32) -> Result<i32> {
-    Ok(
-        sqlx::query("INSERT INTO sessions (user_id) VALUES ($1) RETURNING id")
-            .bind(user_id)
-            .fetch_one(connection_pool)
-            .await?
-            .get(0),
+/// Log a user out everywhere except the session identified by `current_session_token`
+pub async fn delete_other_sessions(
+    connection_pool: &SqlitePool,
+    user_id: i32,
+    current_session_token: &str,
+) -> Result<()> {
+    sqlx::query("delete from sessions where user_id = $1 and token_hash != $2")
+        .bind(user_id)
+        .bind(hash_token(current_session_token))
+        .execute(connection_pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Returns the number of deleted sessions
+pub async fn delete_expired_sessions(connection_pool: &SqlitePool) -> Result<u64> {
+    let result = sqlx::query(
+        "delete from sessions where expires_at <= unixepoch() or last_seen_at + idle_timeout <= unixepoch()",
     )
+    .execute(connection_pool)
+    .await?;
+
+    Ok(result.rows_affected())
 }
 
-pub async fn delete_session_by_id(connection_pool: &SqlitePool, session_id: i32) -> Result<()> {
-    sqlx::query("delete from sessions where id = $1")
-        .bind(session_id)
+pub async fn delete_session_by_token(
+    connection_pool: &SqlitePool,
+    session_token: &str,
+) -> Result<()> {
+    sqlx::query("delete from sessions where token_hash = $1")
+        .bind(hash_token(session_token))
         .execute(connection_pool)
         .await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn emails_are_looked_up_in_any_case() {
+        let connection_pool = test_pool().await;
+
+        assert!(check_email_exists(&connection_pool, "Tempo@TEMPO.com")
+            .await
+            .unwrap());
+        let credentials = get_credentials(&connection_pool, "SOLOMON@tempo.com")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(credentials.id, 2);
+    }
+
+    #[tokio::test]
+    async fn stale_registrations_give_their_address_up() {
+        let connection_pool = test_pool().await;
+        let squatter_id = create_user(&connection_pool, "new@tempo.com", "Squatter", "hash")
+            .await
+            .unwrap()
+            .unwrap();
+        follows::follow(&connection_pool, squatter_id, 1)
+            .await
+            .unwrap();
+        assert!(check_email_exists(&connection_pool, "new@tempo.com")
+            .await
+            .unwrap());
+
+        sqlx::query("update users set created_at = created_at - $1 where id = $2")
+            .bind(UNCONFIRMED_REGISTRATION_HOLD)
+            .bind(squatter_id)
+            .execute(&connection_pool)
+            .await
+            .unwrap();
+        assert!(!check_email_exists(&connection_pool, "new@tempo.com")
+            .await
+            .unwrap());
+
+        let user_id = create_user(&connection_pool, "new@tempo.com", "Owner", "hash")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(get_user_by_id(&connection_pool, squatter_id)
+            .await
+            .unwrap()
+            .is_none());
+        let credentials = get_credentials(&connection_pool, "new@tempo.com")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(credentials.id, user_id);
+
+        // Verified addresses are held for good
+        assert!(check_email_exists(&connection_pool, "tempo@tempo.com")
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn addresses_are_taken_once_in_any_case() {
+        let connection_pool = test_pool().await;
+
+        let user_id = create_user(&connection_pool, "TEMPO@tempo.com", "Copy", "hash")
+            .await
+            .unwrap();
+        assert_eq!(user_id, None);
+        assert!(!update_email(&connection_pool, 2, "Tempo@Tempo.com")
+            .await
+            .unwrap());
+        let user = get_user_by_id(&connection_pool, 2).await.unwrap().unwrap();
+        assert_eq!(user.email, "solomon@tempo.com");
+    }
+}
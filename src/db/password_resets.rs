@@ -0,0 +1,143 @@
+use anyhow::Result;
+use sqlx::{Row, SqlitePool};
+
+use crate::helpers::{generate_token, hash_token};
+
+/// How long a mailed reset link stays valid, in seconds
+pub const RESET_TOKEN_LIFETIME: i64 = 60 * 60;
+
+/// New reset token for the user, any earlier unused ones stop working
+pub async fn create_reset(connection_pool: &SqlitePool, user_id: i32) -> Result<String> {
+    let token = generate_token();
+
+    let mut transaction = connection_pool.begin().await?;
+
+    sqlx::query("delete from password_resets where user_id = $1 and used_at is null")
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await?;
+
+    sqlx::query(
+        "insert into password_resets (user_id, token_hash, created_at, expires_at) values ($1, $2, unixepoch(), unixepoch() + $3)",
+    )
+    .bind(user_id)
+    .bind(hash_token(&token))
+    .bind(RESET_TOKEN_LIFETIME)
+    .execute(&mut *transaction)
+    .await?;
+
+    transaction.commit().await?;
+
+    Ok(token)
+}
+
+/// Whether `token` can still be used to reset a password
+pub async fn is_valid(connection_pool: &SqlitePool, token: &str) -> Result<bool> {
+    let row = sqlx::query(
+        "select id from password_resets where token_hash = $1 and used_at is null and expires_at > unixepoch()",
+    )
+    .bind(hash_token(token))
+    .fetch_optional(connection_pool)
+    .await?;
+
+    Ok(row.is_some())
+}
+
+/// Use up `token` to set a new password and log the user out everywhere,
+/// including logins waiting for a second factor.
+/// `None` when the token is unknown, used or expired.
+pub async fn complete_reset(
+    connection_pool: &SqlitePool,
+    token: &str,
+    password_hash: &str,
+) -> Result<Option<i32>> {
+    let mut transaction = connection_pool.begin().await?;
+
+    // Use the token up first, so two requests can't both use it
+    let user_id: Option<i32> = sqlx::query(
+        "update password_resets set used_at = unixepoch() where token_hash = $1 and used_at is null and expires_at > unixepoch() returning user_id",
+    )
+    .bind(hash_token(token))
+    .fetch_optional(&mut *transaction)
+    .await?
+    .map(|row| row.get(0));
+
+    let Some(user_id) = user_id else {
+        return Ok(None);
+    };
+
+    sqlx::query("update users set password = $1 where id = $2")
+        .bind(password_hash)
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await?;
+
+    sqlx::query("delete from sessions where user_id = $1")
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await?;
+    sqlx::query("delete from login_challenges where user_id = $1")
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await?;
+
+    transaction.commit().await?;
+
+    Ok(Some(user_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{create_session, test_pool, two_factor, DEFAULT_SESSION_LIFETIME};
+
+    #[tokio::test]
+    async fn reset_is_single_use_and_ends_sessions() {
+        let connection_pool = test_pool().await;
+        create_session(&connection_pool, 1, &DEFAULT_SESSION_LIFETIME, None, None)
+            .await
+            .unwrap();
+        two_factor::create_challenge(&connection_pool, 1, false)
+            .await
+            .unwrap();
+
+        let token = create_reset(&connection_pool, 1).await.unwrap();
+        assert!(is_valid(&connection_pool, &token).await.unwrap());
+
+        let user_id = complete_reset(&connection_pool, &token, "new hash")
+            .await
+            .unwrap();
+        assert_eq!(user_id, Some(1));
+
+        assert!(!is_valid(&connection_pool, &token).await.unwrap());
+        let reused = complete_reset(&connection_pool, &token, "other hash")
+            .await
+            .unwrap();
+        assert_eq!(reused, None);
+
+        let sessions: i64 = sqlx::query("select count(*) from sessions where user_id = 1")
+            .fetch_one(&connection_pool)
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(sessions, 0);
+        let challenges: i64 =
+            sqlx::query("select count(*) from login_challenges where user_id = 1")
+                .fetch_one(&connection_pool)
+                .await
+                .unwrap()
+                .get(0);
+        assert_eq!(challenges, 0);
+    }
+
+    #[tokio::test]
+    async fn new_reset_replaces_unused_ones() {
+        let connection_pool = test_pool().await;
+
+        let first = create_reset(&connection_pool, 1).await.unwrap();
+        let second = create_reset(&connection_pool, 1).await.unwrap();
+
+        assert!(!is_valid(&connection_pool, &first).await.unwrap());
+        assert!(is_valid(&connection_pool, &second).await.unwrap());
+    }
+}
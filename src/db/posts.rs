@@ -1,28 +1,47 @@
-use anyhow::Result;
-use sqlx::{FromRow, Row, SqlitePool};
+use std::{fmt, str::FromStr};
 
+use anyhow::{anyhow, Result};
+use sqlx::{Executor, FromRow, Row, Sqlite, SqliteConnection, SqlitePool};
+
+use crate::timestamp::Timestamp;
+
+/// `likes_count` and `comments_count` are maintained by triggers on `likes` and `comments`
 #[derive(FromRow, Debug)]
 pub struct Post {
     pub id: i32,
     pub body: String,
     pub author: String,
+    pub author_id: i32,
+    /// Whether the user the post was fetched for wrote it
+    pub is_author: bool,
+    /// Whether the user the post was fetched for follows its author
+    pub following_author: bool,
+    pub edited_at: Option<Timestamp>,
+    pub created_at: Timestamp,
     pub comments_count: i32,
     pub likes_count: i32,
     pub liked: bool,
+    /// Hidden by a moderator, only fetched for moderators
+    pub hidden: bool,
 }
 
-#[derive(FromRow, Debug)]
-pub struct Like {
-    pub id: i32,
-    pub user_id: i32,
-    pub post_id: i32,
-}
-
+/// Hidden posts are `None` unless `include_hidden` is set
 pub async fn get_by_id(
     connection_pool: &SqlitePool,
     user_id: Option<i32>,
+    include_hidden: bool,
+    post_id: i32,
+) -> Result<Option<Post>> {
+    fetch_by_id(connection_pool, user_id, include_hidden, post_id).await
+}
+
+/// `get_by_id` which also runs inside transactions
+async fn fetch_by_id<'e, E: Executor<'e, Database = Sqlite>>(
+    executor: E,
+    user_id: Option<i32>,
+    include_hidden: bool,
     post_id: i32,
-) -> Result<Post> {
+) -> Result<Option<Post>> {
     let user_id = user_id.unwrap_or(0);
 
     let query = "
@@ -30,148 +49,452 @@ SELECT
     p.id, 
     p.body, 
     u.name AS author, 
-    COUNT(c.id) AS comments_count,
-    COUNT(l.id) AS likes_count,
-    CASE WHEN SUM(l.user_id = $1) > 0 THEN 1 ELSE 0 END AS liked
+    p.author_id,
+    p.author_id = $1 AS is_author,
+    EXISTS (SELECT 1 FROM follows f WHERE f.follower_id = $1 AND f.followee_id = p.author_id) AS following_author,
+    p.edited_at,
+    p.created_at,
+    p.comments_count,
+    p.likes_count,
+    EXISTS (SELECT 1 FROM likes l WHERE l.post_id = p.id AND l.user_id = $1) AS liked,
+    p.hidden_at IS NOT NULL AS hidden
 FROM 
     posts p
 JOIN 
     users u ON u.id = p.author_id
-LEFT JOIN 
-    likes l ON l.post_id = p.id
-LEFT JOIN
-    comments c on c.post_id = p.id
 WHERE 
-    p.id = $2 -- Replace $2 with the ID of the post you want to fetch
-GROUP BY 
-    p.id, p.body, u.name;
+    p.id = $2
+    AND ($3 OR p.hidden_at IS NULL);
 ";
 
     Ok(sqlx::query_as::<_, Post>(query)
         .bind(user_id)
         .bind(post_id)
-        .fetch_one(connection_pool)
+        .bind(include_hidden)
+        .fetch_optional(executor)
         .await?)
 }
 
-/// Get all posts
-/// `user_id` to determine if user liked a post
-pub async fn get_all(connection_pool: &SqlitePool, user_id: Option<i32>) -> Result<Vec<Post>> {
+pub const DEFAULT_PAGE_SIZE: i64 = 20;
+pub const MAX_PAGE_SIZE: i64 = 50;
+
+/// Position in the feed, posts are ordered by `(created_at, id)` newest first.
+/// Serialized as `<created_at>_<id>` for the `before` query parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor {
+    pub created_at: i64,
+    pub id: i32,
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}_{}", self.created_at, self.id)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = anyhow::Error;
+
+    fn from_str(cursor: &str) -> Result<Self> {
+        let (created_at, id) = cursor
+            .split_once('_')
+            .ok_or_else(|| anyhow!("Malformed cursor: {cursor}"))?;
+
+        Ok(Cursor {
+            created_at: created_at.parse()?,
+            id: id.parse()?,
+        })
+    }
+}
+
+/// Which posts a page is taken from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feed {
+    Global,
+    /// Posts by authors the user follows
+    Following(i32),
+    /// Posts by a single author
+    Author(i32),
+}
+
+pub struct Page {
+    pub posts: Vec<Post>,
+    /// Where the next page starts, `None` on the last page
+    pub next_cursor: Option<Cursor>,
+}
+
+/// Get a page of posts from `feed`, newest first, starting after `before`
+/// `user_id` to determine if user liked a post, hidden posts are skipped unless `include_hidden`
+pub async fn get_page(
+    connection_pool: &SqlitePool,
+    user_id: Option<i32>,
+    include_hidden: bool,
+    feed: Feed,
+    before: Option<Cursor>,
+    limit: i64,
+) -> Result<Page> {
     let user_id = user_id.unwrap_or(0);
+    let limit = limit.clamp(1, MAX_PAGE_SIZE);
 
     let query = "
 SELECT 
     p.id, 
     p.body, 
     u.name AS author, 
-    COUNT(c.id) AS comments_count,
-    COUNT(l.id) AS likes_count,
-    CASE WHEN SUM(l.user_id = $1) > 0 THEN 1 ELSE 0 END AS liked
+    p.author_id,
+    p.author_id = $1 AS is_author,
+    EXISTS (SELECT 1 FROM follows f WHERE f.follower_id = $1 AND f.followee_id = p.author_id) AS following_author,
+    p.edited_at,
+    p.created_at,
+    p.comments_count,
+    p.likes_count,
+    EXISTS (SELECT 1 FROM likes l WHERE l.post_id = p.id AND l.user_id = $1) AS liked,
+    p.hidden_at IS NOT NULL AS hidden
 FROM 
     posts p
 JOIN 
     users u ON u.id = p.author_id
-LEFT JOIN 
-    likes l ON l.post_id = p.id
-LEFT JOIN
-    comments c on c.post_id = p.id
-GROUP BY 
-    p.id, p.body, u.name;
+WHERE
+    ($2 IS NULL OR (p.created_at, p.id) < ($2, $3))
+    AND ($5 IS NULL OR p.author_id IN (SELECT followee_id FROM follows WHERE follower_id = $5))
+    AND ($6 IS NULL OR p.author_id = $6)
+    AND ($7 OR p.hidden_at IS NULL)
+ORDER BY
+    p.created_at DESC, p.id DESC
+LIMIT $4;
 ";
+    let (follower_id, author_id) = match feed {
+        Feed::Global => (None, None),
+        Feed::Following(follower_id) => (Some(follower_id), None),
+        Feed::Author(author_id) => (None, Some(author_id)),
+    };
 
-    Ok(sqlx::query_as::<_, Post>(query)
+    // One extra row tells whether there is a next page
+    let mut posts = sqlx::query_as::<_, Post>(query)
         .bind(user_id)
+        .bind(before.map(|cursor| cursor.created_at))
+        .bind(before.map(|cursor| cursor.id))
+        .bind(limit + 1)
+        .bind(follower_id)
+        .bind(author_id)
+        .bind(include_hidden)
         .fetch_all(connection_pool)
-        .await?)
+        .await?;
+
+    let next_cursor = if posts.len() as i64 > limit {
+        posts.truncate(limit as usize);
+        posts.last().map(|post| Cursor {
+            created_at: post.created_at.0,
+            id: post.id,
+        })
+    } else {
+        None
+    };
+
+    Ok(Page { posts, next_cursor })
 }
 
 pub async fn create_post(connection_pool: &SqlitePool, author_id: i32, body: &str) -> Result<i32> {
+    Ok(sqlx::query(
+        "insert into posts (author_id, body, created_at) values ($1, $2, unixepoch()) returning id",
+    )
+    .bind(author_id)
+    .bind(body)
+    .fetch_one(connection_pool)
+    .await?
+    .get(0))
+}
+
+/// Hidden posts only exist for moderators, pass `include_hidden` for them
+pub async fn exists(
+    connection_pool: &SqlitePool,
+    include_hidden: bool,
+    post_id: i32,
+) -> Result<bool> {
     Ok(
-        sqlx::query("insert into posts (author_id, body) values ($1, $2) returning id")
-            .bind(author_id)
-            .bind(body)
-            .fetch_one(connection_pool)
+        sqlx::query("select id from posts where id = $1 and ($2 or hidden_at is null)")
+            .bind(post_id)
+            .bind(include_hidden)
+            .fetch_optional(connection_pool)
             .await?
-            .get(0),
+            .is_some(),
     )
 }
 
-pub async fn like_post(connection_pool: &SqlitePool, user_id: i32, post_id: i32) -> Result<Post> {
-    let query = "
--- Insert a like
-INSERT INTO likes (user_id, post_id)
-VALUES ($1, $2); -- Replace with the actual user_id and post_id
-
--- Retrieve the post with the same fields
-SELECT 
-    p.id, 
-    p.body, 
-    u.name AS author, 
-    COUNT(l.id) AS likes_count,
-    CASE WHEN SUM(l.user_id = $1) > 0 THEN 1 ELSE 0 END AS liked
-FROM 
-    posts p
-JOIN 
-    users u ON u.id = p.author_id
-LEFT JOIN 
-    likes l ON l.post_id = p.id
-WHERE
-    p.id = $2; -- Replace with the actual post_id
-GROUP BY 
-    p.id, p.body, u.name;
-";
+pub async fn get_author_id(
+    connection_pool: &SqlitePool,
+    include_hidden: bool,
+    post_id: i32,
+) -> Result<Option<i32>> {
+    Ok(
+        sqlx::query("select author_id from posts where id = $1 and ($2 or hidden_at is null)")
+            .bind(post_id)
+            .bind(include_hidden)
+            .fetch_optional(connection_pool)
+            .await?
+            .map(|row| row.get(0)),
+    )
+}
 
-    Ok(sqlx::query_as::<_, Post>(query)
-        .bind(user_id)
+pub async fn update_post(connection_pool: &SqlitePool, post_id: i32, body: &str) -> Result<()> {
+    sqlx::query("update posts set body = $1, edited_at = unixepoch() where id = $2")
+        .bind(body)
         .bind(post_id)
-        .fetch_one(connection_pool)
-        .await?)
+        .execute(connection_pool)
+        .await?;
+
+    Ok(())
 }
 
-pub async fn remove_like(connection_pool: &SqlitePool, user_id: i32, post_id: i32) -> Result<Post> {
-    let query = "
--- Insert a like
-DELETE FROM likes
-    WHERE user_id = $1
-      AND post_id = $2;
+/// Deletes the post together with its likes and comments
+pub async fn delete_post(connection_pool: &SqlitePool, post_id: i32) -> Result<()> {
+    let mut transaction = connection_pool.begin().await?;
+    delete_post_in(&mut transaction, post_id).await?;
+    transaction.commit().await?;
 
--- Retrieve the post with the same fields
-SELECT 
-    p.id, 
-    p.body, 
-    u.name AS author, 
-    COUNT(l.id) AS likes_count,
-    CASE WHEN SUM(l.user_id = $1) > 0 THEN 1 ELSE 0 END AS liked
-FROM 
-    posts p
-JOIN 
-    users u ON u.id = p.author_id
-LEFT JOIN 
-    likes l ON l.post_id = p.id
-WHERE
-    p.id = $2; -- Replace with the actual post_id
-GROUP BY 
-    p.id, p.body, u.name;
-";
+    Ok(())
+}
 
-    Ok(sqlx::query_as::<_, Post>(query)
-        .bind(user_id)
+/// `delete_post` as part of a larger transaction
+pub async fn delete_post_in(connection: &mut SqliteConnection, post_id: i32) -> Result<()> {
+    sqlx::query("delete from likes where post_id = $1")
         .bind(post_id)
-        .fetch_one(connection_pool)
-        .await?)
+        .execute(&mut *connection)
+        .await?;
+    sqlx::query("delete from comments where post_id = $1")
+        .bind(post_id)
+        .execute(&mut *connection)
+        .await?;
+    sqlx::query("delete from posts where id = $1")
+        .bind(post_id)
+        .execute(&mut *connection)
+        .await?;
+
+    Ok(())
+}
+
+/// Idempotent, liking a post twice keeps a single like.
+/// Returns the post as seen after the change, `None` if it doesn't exist.
+pub async fn like_post(
+    connection_pool: &SqlitePool,
+    user_id: i32,
+    include_hidden: bool,
+    post_id: i32,
+) -> Result<Option<Post>> {
+    let mut transaction = connection_pool.begin().await?;
+
+    sqlx::query(
+        "insert or ignore into likes (user_id, post_id, created_at)
+         select $1, $2, unixepoch() where exists (select 1 from posts where id = $2 and ($3 or hidden_at is null))",
+    )
+    .bind(user_id)
+    .bind(post_id)
+    .bind(include_hidden)
+    .execute(&mut *transaction)
+    .await?;
+
+    let post = fetch_by_id(&mut *transaction, Some(user_id), include_hidden, post_id).await?;
+    transaction.commit().await?;
+
+    Ok(post)
+}
+
+/// Idempotent, removing a like that doesn't exist is a no-op.
+/// Returns the post as seen after the change, `None` if it doesn't exist.
+pub async fn remove_like(
+    connection_pool: &SqlitePool,
+    user_id: i32,
+    include_hidden: bool,
+    post_id: i32,
+) -> Result<Option<Post>> {
+    let mut transaction = connection_pool.begin().await?;
+
+    sqlx::query(
+        "delete from likes where user_id = $1 and post_id = $2
+         and exists (select 1 from posts where id = $2 and ($3 or hidden_at is null))",
+    )
+    .bind(user_id)
+    .bind(post_id)
+    .bind(include_hidden)
+    .execute(&mut *transaction)
+    .await?;
+
+    let post = fetch_by_id(&mut *transaction, Some(user_id), include_hidden, post_id).await?;
+    transaction.commit().await?;
+
+    Ok(post)
 }
 
 #[derive(FromRow, Debug)]
 pub struct Comment {
+    pub id: i32,
     pub body: String,
     pub author: String,
+    pub author_id: i32,
+    pub created_at: Timestamp,
+    /// Hidden by a moderator, only fetched for moderators
+    pub hidden: bool,
 }
 
-pub async fn comments(connection_pool: &SqlitePool, post_id: i32) -> Result<Vec<Comment>> {
-    let query = "select body, u.name as author from comments c join users u on c.author_id = u.id where c.post_id = $1";
+/// Hidden comments are left out unless `include_hidden` is set
+/// Comments on a hidden post are as good as hidden themselves
+pub async fn comments(
+    connection_pool: &SqlitePool,
+    post_id: i32,
+    include_hidden: bool,
+) -> Result<Vec<Comment>> {
+    let query = "select c.id, c.body, u.name as author, c.author_id, c.created_at, c.hidden_at is not null as hidden from comments c join users u on c.author_id = u.id join posts p on p.id = c.post_id where c.post_id = $1 and ($2 or (c.hidden_at is null and p.hidden_at is null)) order by c.created_at, c.id";
 
     Ok(sqlx::query_as::<_, Comment>(query)
         .bind(post_id)
+        .bind(include_hidden)
         .fetch_all(connection_pool)
         .await?)
 }
+
+/// Comments on a hidden post are as good as hidden themselves
+pub async fn get_comment_author_id(
+    connection_pool: &SqlitePool,
+    include_hidden: bool,
+    comment_id: i32,
+) -> Result<Option<i32>> {
+    Ok(sqlx::query(
+        "select c.author_id from comments c join posts p on p.id = c.post_id
+         where c.id = $1 and ($2 or (c.hidden_at is null and p.hidden_at is null))",
+    )
+    .bind(comment_id)
+    .bind(include_hidden)
+    .fetch_optional(connection_pool)
+    .await?
+    .map(|row| row.get(0)))
+}
+
+/// Part of a larger transaction, the comment counter is kept by a trigger
+pub async fn delete_comment_in(connection: &mut SqliteConnection, comment_id: i32) -> Result<()> {
+    sqlx::query("delete from comments where id = $1")
+        .bind(comment_id)
+        .execute(&mut *connection)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn create_comment(
+    connection_pool: &SqlitePool,
+    post_id: i32,
+    author_id: i32,
+    body: &str,
+) -> Result<i32> {
+    Ok(sqlx::query(
+        "insert into comments (post_id, author_id, body, created_at) values ($1, $2, $3, unixepoch()) returning id",
+    )
+    .bind(post_id)
+    .bind(author_id)
+    .bind(body)
+    .fetch_one(connection_pool)
+    .await?
+    .get(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_pool;
+
+    // Seeded by the init migration: post 1 has 2 likes, post 2 has 1, post 3 has 2
+    async fn seed_comments(connection_pool: &SqlitePool) {
+        create_comment(connection_pool, 1, 1, "first")
+            .await
+            .unwrap();
+        create_comment(connection_pool, 1, 2, "second")
+            .await
+            .unwrap();
+        create_comment(connection_pool, 1, 2, "third")
+            .await
+            .unwrap();
+        create_comment(connection_pool, 2, 1, "only").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn get_by_id_counts_likes_and_comments_independently() {
+        let connection_pool = test_pool().await;
+        seed_comments(&connection_pool).await;
+
+        let post = get_by_id(&connection_pool, Some(2), false, 1)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(post.likes_count, 2);
+        assert_eq!(post.comments_count, 3);
+        assert!(post.liked);
+
+        let post = get_by_id(&connection_pool, Some(1), false, 2)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(post.likes_count, 1);
+        assert_eq!(post.comments_count, 1);
+        assert!(!post.liked);
+    }
+
+    #[tokio::test]
+    async fn get_page_counts_likes_and_comments_independently() {
+        let connection_pool = test_pool().await;
+        seed_comments(&connection_pool).await;
+
+        let page = get_page(
+            &connection_pool,
+            None,
+            false,
+            Feed::Global,
+            None,
+            DEFAULT_PAGE_SIZE,
+        )
+        .await
+        .unwrap();
+        let counts = |post_id: i32| {
+            let post = page.posts.iter().find(|post| post.id == post_id).unwrap();
+            (post.likes_count, post.comments_count)
+        };
+
+        assert_eq!(counts(1), (2, 3));
+        assert_eq!(counts(2), (1, 1));
+        assert_eq!(counts(3), (2, 0));
+        assert_eq!(counts(4), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn like_counts_stay_correct_with_comments() {
+        let connection_pool = test_pool().await;
+        seed_comments(&connection_pool).await;
+
+        let post = like_post(&connection_pool, 1, false, 2)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((post.likes_count, post.comments_count), (2, 1));
+
+        let post = remove_like(&connection_pool, 1, false, 1)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((post.likes_count, post.comments_count), (1, 3));
+    }
+
+    #[tokio::test]
+    async fn comment_counter_follows_deletes() {
+        let connection_pool = test_pool().await;
+        seed_comments(&connection_pool).await;
+
+        sqlx::query("delete from comments where post_id = 1 and author_id = 2")
+            .execute(&connection_pool)
+            .await
+            .unwrap();
+
+        let post = get_by_id(&connection_pool, None, false, 1)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((post.likes_count, post.comments_count), (2, 1));
+    }
+}
@@ -0,0 +1,238 @@
+use anyhow::Result;
+use sqlx::{Row, SqlitePool};
+
+/// How many attempts a key gets before it is locked out, and for how long
+pub struct Limit {
+    /// Attempts allowed within `window` before lockouts start
+    pub free_attempts: i64,
+    /// First lockout in seconds, doubled with every further attempt
+    pub base_lockout: i64,
+    pub max_lockout: i64,
+    /// Seconds without attempts after which the count starts over
+    pub window: i64,
+}
+
+/// Failed logins for a single account
+pub const LOGIN_ACCOUNT_LIMIT: Limit = Limit {
+    free_attempts: 5,
+    base_lockout: 30,
+    max_lockout: 60 * 60,
+    window: 15 * 60,
+};
+
+/// Failed logins from a single IP, across accounts
+pub const LOGIN_IP_LIMIT: Limit = Limit {
+    free_attempts: 20,
+    base_lockout: 30,
+    max_lockout: 60 * 60,
+    window: 15 * 60,
+};
+
+/// Submitted registrations, which tell whether an email is taken, from a single IP
+pub const EMAIL_PROBE_LIMIT: Limit = Limit {
+    free_attempts: 30,
+    base_lockout: 30,
+    max_lockout: 60 * 60,
+    window: 15 * 60,
+};
+
+/// Live checks of whether an email is registered while it is being typed, from a single IP.
+/// Every pause in typing costs one, hence more of them than submissions.
+pub const EMAIL_CHECK_LIMIT: Limit = Limit {
+    free_attempts: 100,
+    base_lockout: 30,
+    max_lockout: 60 * 60,
+    window: 15 * 60,
+};
+
+/// Password reset mails, per IP and per account
+pub const PASSWORD_RESET_LIMIT: Limit = Limit {
+    free_attempts: 3,
+    base_lockout: 60,
+    max_lockout: 60 * 60,
+    window: 60 * 60,
+};
+
+/// Verification mails sent again on request, per user
+pub const VERIFICATION_MAIL_LIMIT: Limit = Limit {
+    free_attempts: 3,
+    base_lockout: 60,
+    max_lockout: 60 * 60,
+    window: 60 * 60,
+};
+
+impl Limit {
+    /// Lockout after the `attempts`th attempt in a row
+    fn lockout(&self, attempts: i64) -> i64 {
+        let over = attempts - self.free_attempts;
+        if over <= 0 {
+            return 0;
+        }
+        // The cap is reached long before the shift could overflow
+        let factor = 1i64 << (over - 1).min(20);
+        (self.base_lockout * factor).min(self.max_lockout)
+    }
+}
+
+/// Count an attempt for `key` before it is made, locking it out once it runs past `limit`.
+/// A locked out key isn't counted, `Some` tells the seconds until it may try again.
+/// Checking and counting happen in one write, so parallel requests can't all slip past the check.
+pub async fn record_attempt(
+    connection_pool: &SqlitePool,
+    key: &str,
+    limit: &Limit,
+) -> Result<Option<i64>> {
+    let mut transaction = connection_pool.begin().await?;
+
+    let attempts: Option<i64> = sqlx::query(
+        "
+INSERT INTO rate_limits (key, attempts, last_attempt_at) VALUES ($1, 1, unixepoch())
+ON CONFLICT (key) DO UPDATE SET
+    attempts = CASE WHEN last_attempt_at + $2 < unixepoch() THEN 1 ELSE attempts + 1 END,
+    last_attempt_at = unixepoch()
+WHERE locked_until <= unixepoch()
+RETURNING attempts;
+",
+    )
+    .bind(key)
+    .bind(limit.window)
+    .fetch_optional(&mut *transaction)
+    .await?
+    .map(|row| row.get(0));
+
+    let Some(attempts) = attempts else {
+        let remaining: i64 =
+            sqlx::query("select locked_until - unixepoch() from rate_limits where key = $1")
+                .bind(key)
+                .fetch_one(&mut *transaction)
+                .await?
+                .get(0);
+        transaction.commit().await?;
+        return Ok(Some(remaining));
+    };
+
+    let lockout = limit.lockout(attempts);
+    if lockout > 0 {
+        sqlx::query("update rate_limits set locked_until = unixepoch() + $1 where key = $2")
+            .bind(lockout)
+            .bind(key)
+            .execute(&mut *transaction)
+            .await?;
+    }
+
+    transaction.commit().await?;
+
+    Ok(None)
+}
+
+/// Take back an attempt of `key` which turned out fine, without forgetting the others
+pub async fn refund_attempt(connection_pool: &SqlitePool, key: &str) -> Result<()> {
+    sqlx::query("update rate_limits set attempts = max(attempts - 1, 0) where key = $1")
+        .bind(key)
+        .execute(connection_pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Forget the attempts of `key`, e.g. after a successful login
+pub async fn clear(connection_pool: &SqlitePool, key: &str) -> Result<()> {
+    sqlx::query("delete from rate_limits where key = $1")
+        .bind(key)
+        .execute(connection_pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Delete counters which are neither locked out nor within any window anymore
+pub async fn delete_stale(connection_pool: &SqlitePool) -> Result<u64> {
+    let window = [
+        &LOGIN_ACCOUNT_LIMIT,
+        &LOGIN_IP_LIMIT,
+        &EMAIL_PROBE_LIMIT,
+        &EMAIL_CHECK_LIMIT,
+        &PASSWORD_RESET_LIMIT,
+        &VERIFICATION_MAIL_LIMIT,
+    ]
+    .iter()
+    .map(|limit| limit.window)
+    .max()
+    .unwrap_or_default();
+
+    let result = sqlx::query(
+        "delete from rate_limits where locked_until <= unixepoch() and last_attempt_at + $1 < unixepoch()",
+    )
+    .bind(window)
+    .execute(connection_pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_pool;
+
+    const LIMIT: Limit = Limit {
+        free_attempts: 2,
+        base_lockout: 30,
+        max_lockout: 100,
+        window: 60,
+    };
+
+    #[test]
+    fn lockout_doubles_up_to_the_cap() {
+        let lockouts: Vec<i64> = (1..=6).map(|attempts| LIMIT.lockout(attempts)).collect();
+        assert_eq!(lockouts, [0, 0, 30, 60, 100, 100]);
+        assert_eq!(LIMIT.lockout(i64::MAX), 100);
+    }
+
+    #[tokio::test]
+    async fn locks_out_after_free_attempts_until_cleared() {
+        let connection_pool = test_pool().await;
+
+        // The attempt running past the limit is still made, it locks out the ones after it
+        for _ in 0..=LIMIT.free_attempts {
+            let locked = record_attempt(&connection_pool, "key", &LIMIT)
+                .await
+                .unwrap();
+            assert_eq!(locked, None);
+        }
+        let locked = record_attempt(&connection_pool, "key", &LIMIT)
+            .await
+            .unwrap();
+        assert!(matches!(locked, Some(29..=30)), "{locked:?}");
+        let locked = record_attempt(&connection_pool, "other", &LIMIT)
+            .await
+            .unwrap();
+        assert_eq!(locked, None);
+
+        clear(&connection_pool, "key").await.unwrap();
+        let locked = record_attempt(&connection_pool, "key", &LIMIT)
+            .await
+            .unwrap();
+        assert_eq!(locked, None);
+    }
+
+    #[tokio::test]
+    async fn parallel_attempts_cant_skip_the_lockout() {
+        let connection_pool = test_pool().await;
+
+        let attempts: Vec<_> = (0..10)
+            .map(|_| {
+                let connection_pool = connection_pool.clone();
+                tokio::spawn(async move { record_attempt(&connection_pool, "key", &LIMIT).await })
+            })
+            .collect();
+        let mut allowed = 0;
+        for attempt in attempts {
+            if attempt.await.unwrap().unwrap().is_none() {
+                allowed += 1;
+            }
+        }
+
+        assert_eq!(allowed, LIMIT.free_attempts + 1);
+    }
+}
@@ -0,0 +1,432 @@
+use anyhow::Result;
+use serde::Deserialize;
+use sqlx::{FromRow, SqliteConnection, SqlitePool};
+
+use super::{admin, posts, Role};
+use crate::timestamp::Timestamp;
+
+/// Most open reports the queue shows at once, oldest first
+pub const QUEUE_SIZE: i64 = 50;
+/// Most recent audit log entries shown under the queue
+pub const AUDIT_LOG_SIZE: i64 = 50;
+
+/// What a report is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Post(i32),
+    Comment(i32),
+}
+
+impl Target {
+    /// `(post_id, comment_id)` columns of the reports table
+    fn ids(self) -> (Option<i32>, Option<i32>) {
+        match self {
+            Target::Post(post_id) => (Some(post_id), None),
+            Target::Comment(comment_id) => (None, Some(comment_id)),
+        }
+    }
+}
+
+/// Idempotent, a second report of the same content by the same user is ignored
+pub async fn create_report(
+    connection_pool: &SqlitePool,
+    reporter_id: i32,
+    target: Target,
+    reason: &str,
+) -> Result<()> {
+    let (post_id, comment_id) = target.ids();
+
+    sqlx::query(
+        "insert or ignore into reports (reporter_id, post_id, comment_id, reason, created_at) values ($1, $2, $3, $4, unixepoch())",
+    )
+    .bind(reporter_id)
+    .bind(post_id)
+    .bind(comment_id)
+    .bind(reason)
+    .execute(connection_pool)
+    .await?;
+
+    Ok(())
+}
+
+/// How a moderator dealt with a report, recorded in the audit log
+#[derive(sqlx::Type, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    /// Nothing wrong with the content
+    Dismiss,
+    Hide,
+    Delete,
+    /// Hide the content and suspend its author
+    Ban,
+}
+
+impl Outcome {
+    /// For audit log lines like "Tempo hid a post by Solomon"
+    pub fn past_tense(self) -> &'static str {
+        match self {
+            Outcome::Dismiss => "dismissed a report on",
+            Outcome::Hide => "hid",
+            Outcome::Delete => "deleted",
+            Outcome::Ban => "banned the author of",
+        }
+    }
+}
+
+#[derive(FromRow, Debug)]
+pub struct OpenReport {
+    pub id: i32,
+    pub reason: String,
+    pub created_at: Timestamp,
+    pub reporter: String,
+    /// The reported post, or the one the reported comment is on
+    pub post_id: i32,
+    /// Set when a comment was reported
+    pub comment_id: Option<i32>,
+    pub content: String,
+    pub author: String,
+    pub author_id: i32,
+    pub author_role: Role,
+    /// Whether the content is hidden already
+    pub hidden: bool,
+}
+
+impl OpenReport {
+    pub fn target(&self) -> Target {
+        match self.comment_id {
+            Some(comment_id) => Target::Comment(comment_id),
+            None => Target::Post(self.post_id),
+        }
+    }
+}
+
+const OPEN_REPORT_QUERY: &str = "
+SELECT
+    r.id,
+    r.reason,
+    r.created_at,
+    reporter.name AS reporter,
+    coalesce(r.post_id, c.post_id) AS post_id,
+    r.comment_id,
+    coalesce(p.body, c.body) AS content,
+    author.name AS author,
+    author.id AS author_id,
+    author.role AS author_role,
+    coalesce(p.hidden_at, c.hidden_at) IS NOT NULL AS hidden
+FROM reports r
+JOIN users reporter ON reporter.id = r.reporter_id
+LEFT JOIN posts p ON p.id = r.post_id
+LEFT JOIN comments c ON c.id = r.comment_id
+JOIN users author ON author.id = coalesce(p.author_id, c.author_id)
+WHERE r.resolved_at IS NULL
+";
+
+pub async fn open_reports(connection_pool: &SqlitePool) -> Result<Vec<OpenReport>> {
+    let query = format!("{OPEN_REPORT_QUERY} ORDER BY r.created_at, r.id LIMIT $1");
+
+    Ok(sqlx::query_as::<_, OpenReport>(&query)
+        .bind(QUEUE_SIZE)
+        .fetch_all(connection_pool)
+        .await?)
+}
+
+/// `None` once the report was resolved
+pub async fn get_open_report(
+    connection_pool: &SqlitePool,
+    report_id: i32,
+) -> Result<Option<OpenReport>> {
+    let query = format!("{OPEN_REPORT_QUERY} AND r.id = $1");
+
+    Ok(sqlx::query_as::<_, OpenReport>(&query)
+        .bind(report_id)
+        .fetch_optional(connection_pool)
+        .await?)
+}
+
+/// Apply `outcome` to the reported content and resolve every open report about it,
+/// recording it in the audit log
+pub async fn resolve(
+    connection_pool: &SqlitePool,
+    moderator_id: i32,
+    report: &OpenReport,
+    outcome: Outcome,
+) -> Result<()> {
+    let target = report.target();
+    let (post_id, comment_id) = target.ids();
+
+    let mut transaction = connection_pool.begin().await?;
+
+    sqlx::query(
+        "insert into audit_log (moderator_id, outcome, report_id, author_id, post_id, comment_id, content, reason, created_at)
+         values ($1, $2, $3, $4, $5, $6, $7, $8, unixepoch())",
+    )
+    .bind(moderator_id)
+    .bind(outcome)
+    .bind(report.id)
+    .bind(report.author_id)
+    .bind(report.post_id)
+    .bind(report.comment_id)
+    .bind(&report.content)
+    .bind(&report.reason)
+    .execute(&mut *transaction)
+    .await?;
+
+    sqlx::query(
+        "update reports set resolved_at = unixepoch() where resolved_at is null and (post_id = $1 or comment_id = $2)",
+    )
+    .bind(post_id)
+    .bind(comment_id)
+    .execute(&mut *transaction)
+    .await?;
+
+    match (outcome, target) {
+        (Outcome::Dismiss, _) => {}
+        (Outcome::Hide, target) => hide(&mut transaction, target).await?,
+        (Outcome::Ban, target) => {
+            hide(&mut transaction, target).await?;
+            admin::suspend_in(&mut transaction, report.author_id).await?;
+        }
+        (Outcome::Delete, Target::Post(post_id)) => {
+            posts::delete_post_in(&mut transaction, post_id).await?
+        }
+        (Outcome::Delete, Target::Comment(comment_id)) => {
+            posts::delete_comment_in(&mut transaction, comment_id).await?
+        }
+    }
+
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+async fn hide(connection: &mut SqliteConnection, target: Target) -> Result<()> {
+    let (query, id) = match target {
+        Target::Post(post_id) => (
+            "update posts set hidden_at = unixepoch() where id = $1 and hidden_at is null",
+            post_id,
+        ),
+        Target::Comment(comment_id) => (
+            "update comments set hidden_at = unixepoch() where id = $1 and hidden_at is null",
+            comment_id,
+        ),
+    };
+
+    sqlx::query(query).bind(id).execute(connection).await?;
+
+    Ok(())
+}
+
+#[derive(FromRow, Debug)]
+pub struct AuditEntry {
+    pub moderator: String,
+    pub outcome: Outcome,
+    pub author: String,
+    pub author_id: i32,
+    /// The post, or the one the comment was on. It may be gone by now.
+    pub post_id: Option<i32>,
+    /// Set when a comment was reported
+    pub comment_id: Option<i32>,
+    /// Copy of the content when the report was resolved
+    pub content: String,
+    pub reason: String,
+    pub created_at: Timestamp,
+}
+
+/// Most recent entries first
+pub async fn audit_log(connection_pool: &SqlitePool) -> Result<Vec<AuditEntry>> {
+    let query = "
+SELECT moderator.name AS moderator, a.outcome, author.name AS author, a.author_id,
+       a.post_id, a.comment_id, a.content, a.reason, a.created_at
+FROM audit_log a
+JOIN users moderator ON moderator.id = a.moderator_id
+JOIN users author ON author.id = a.author_id
+ORDER BY a.created_at DESC, a.id DESC
+LIMIT $1
+";
+
+    Ok(sqlx::query_as::<_, AuditEntry>(query)
+        .bind(AUDIT_LOG_SIZE)
+        .fetch_all(connection_pool)
+        .await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{posts::Feed, test_pool};
+
+    #[tokio::test]
+    async fn hiding_resolves_every_report_and_hides_from_non_moderators() {
+        let connection_pool = test_pool().await;
+        posts::create_comment(&connection_pool, 3, 1, "nice one")
+            .await
+            .unwrap();
+        create_report(&connection_pool, 1, Target::Post(3), "spam")
+            .await
+            .unwrap();
+        create_report(&connection_pool, 1, Target::Post(3), "spam again")
+            .await
+            .unwrap();
+        create_report(&connection_pool, 2, Target::Post(1), "rude")
+            .await
+            .unwrap();
+
+        let reports = open_reports(&connection_pool).await.unwrap();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].author, "Solomon");
+        assert_eq!(reports[0].reason, "spam");
+
+        resolve(&connection_pool, 1, &reports[0], Outcome::Hide)
+            .await
+            .unwrap();
+
+        let reports = open_reports(&connection_pool).await.unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].target(), Target::Post(1));
+
+        assert!(posts::get_by_id(&connection_pool, None, false, 3)
+            .await
+            .unwrap()
+            .is_none());
+        let post = posts::get_by_id(&connection_pool, None, true, 3)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(post.hidden);
+        assert!(!posts::exists(&connection_pool, false, 3).await.unwrap());
+        assert!(posts::exists(&connection_pool, true, 3).await.unwrap());
+        assert!(posts::like_post(&connection_pool, 2, false, 3)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(posts::comments(&connection_pool, 3, false)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            posts::comments(&connection_pool, 3, true)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let page = posts::get_page(&connection_pool, None, false, Feed::Global, None, 10)
+            .await
+            .unwrap();
+        assert!(page.posts.iter().all(|post| post.id != 3));
+
+        let log = audit_log(&connection_pool).await.unwrap();
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0].outcome, Outcome::Hide);
+        assert_eq!(log[0].content, "Hi this is Solomon");
+    }
+
+    #[tokio::test]
+    async fn deleted_comments_stay_in_the_audit_log() {
+        let connection_pool = test_pool().await;
+        let comment_id = posts::create_comment(&connection_pool, 1, 2, "rude comment")
+            .await
+            .unwrap();
+        create_report(&connection_pool, 1, Target::Comment(comment_id), "rude")
+            .await
+            .unwrap();
+
+        let report = open_reports(&connection_pool).await.unwrap().remove(0);
+        assert_eq!(report.post_id, 1);
+        resolve(&connection_pool, 1, &report, Outcome::Delete)
+            .await
+            .unwrap();
+
+        assert!(posts::comments(&connection_pool, 1, true)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(open_reports(&connection_pool).await.unwrap().is_empty());
+
+        let log = audit_log(&connection_pool).await.unwrap();
+        assert_eq!(log[0].comment_id, Some(comment_id));
+        assert_eq!(log[0].content, "rude comment");
+    }
+
+    #[tokio::test]
+    async fn likes_on_hidden_posts_stay_for_non_moderators() {
+        let connection_pool = test_pool().await;
+        create_report(&connection_pool, 2, Target::Post(1), "rude")
+            .await
+            .unwrap();
+        let report = open_reports(&connection_pool).await.unwrap().remove(0);
+        resolve(&connection_pool, 2, &report, Outcome::Hide)
+            .await
+            .unwrap();
+
+        // Post 1 is liked by both seeded users
+        assert!(posts::remove_like(&connection_pool, 1, false, 1)
+            .await
+            .unwrap()
+            .is_none());
+        let post = posts::get_by_id(&connection_pool, Some(1), true, 1)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(post.liked);
+    }
+
+    #[tokio::test]
+    async fn hidden_comments_are_not_counted() {
+        let connection_pool = test_pool().await;
+        let comments_count = || async {
+            posts::get_by_id(&connection_pool, None, false, 1)
+                .await
+                .unwrap()
+                .unwrap()
+                .comments_count
+        };
+        let before = comments_count().await;
+
+        let comment_id = posts::create_comment(&connection_pool, 1, 2, "rude comment")
+            .await
+            .unwrap();
+        assert_eq!(comments_count().await, before + 1);
+        create_report(&connection_pool, 1, Target::Comment(comment_id), "rude")
+            .await
+            .unwrap();
+        let report = open_reports(&connection_pool).await.unwrap().remove(0);
+        resolve(&connection_pool, 1, &report, Outcome::Hide)
+            .await
+            .unwrap();
+        assert_eq!(comments_count().await, before);
+
+        // Deleting it later doesn't count it off a second time
+        let mut connection = connection_pool.acquire().await.unwrap();
+        posts::delete_comment_in(&mut connection, comment_id)
+            .await
+            .unwrap();
+        drop(connection);
+        assert_eq!(comments_count().await, before);
+    }
+
+    #[tokio::test]
+    async fn banning_suspends_the_author() {
+        let connection_pool = test_pool().await;
+        create_report(&connection_pool, 1, Target::Post(4), "bad taste")
+            .await
+            .unwrap();
+
+        let report = open_reports(&connection_pool).await.unwrap().remove(0);
+        resolve(&connection_pool, 1, &report, Outcome::Ban)
+            .await
+            .unwrap();
+
+        let account = admin::get_account(&connection_pool, 2)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(account.suspended_at.is_some());
+        assert!(posts::get_by_id(&connection_pool, None, false, 4)
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
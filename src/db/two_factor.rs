@@ -0,0 +1,292 @@
+use anyhow::Result;
+use sqlx::{FromRow, Row, SqlitePool};
+
+use crate::helpers::{generate_token, hash_token};
+
+/// How long the code entry after a correct password may take, in seconds
+pub const CHALLENGE_LIFETIME: i64 = 5 * 60;
+/// Wrong codes a single challenge takes before the password is needed again
+pub const MAX_CHALLENGE_ATTEMPTS: i64 = 5;
+
+#[derive(FromRow)]
+pub struct TotpState {
+    /// Set once enrolment started
+    pub secret: Option<String>,
+    pub enabled: bool,
+}
+
+pub async fn get_state(connection_pool: &SqlitePool, user_id: i32) -> Result<TotpState> {
+    Ok(sqlx::query_as::<_, TotpState>(
+        "select totp_secret as secret, totp_enabled_at is not null as enabled from users where id = $1",
+    )
+    .bind(user_id)
+    .fetch_one(connection_pool)
+    .await?)
+}
+
+/// Store a secret which isn't used for logins until `enable`
+pub async fn start_enrolment(
+    connection_pool: &SqlitePool,
+    user_id: i32,
+    secret: &str,
+) -> Result<()> {
+    sqlx::query("update users set totp_secret = $1 where id = $2 and totp_enabled_at is null")
+        .bind(secret)
+        .bind(user_id)
+        .execute(connection_pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Turn on two-factor logins, replacing any recovery codes with `recovery_codes`.
+/// `step` is the time step of the code which confirmed the enrolment.
+pub async fn enable(
+    connection_pool: &SqlitePool,
+    user_id: i32,
+    step: i64,
+    recovery_codes: &[String],
+) -> Result<()> {
+    let mut transaction = connection_pool.begin().await?;
+
+    sqlx::query(
+        "update users set totp_enabled_at = unixepoch(), totp_last_step = $1 where id = $2",
+    )
+    .bind(step)
+    .bind(user_id)
+    .execute(&mut *transaction)
+    .await?;
+
+    sqlx::query("delete from recovery_codes where user_id = $1")
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await?;
+
+    for code in recovery_codes {
+        sqlx::query("insert into recovery_codes (user_id, code_hash) values ($1, $2)")
+            .bind(user_id)
+            .bind(hash_token(code))
+            .execute(&mut *transaction)
+            .await?;
+    }
+
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+pub async fn disable(connection_pool: &SqlitePool, user_id: i32) -> Result<()> {
+    let mut transaction = connection_pool.begin().await?;
+
+    sqlx::query(
+        "update users set totp_secret = null, totp_enabled_at = null, totp_last_step = 0 where id = $1",
+    )
+    .bind(user_id)
+    .execute(&mut *transaction)
+    .await?;
+
+    sqlx::query("delete from recovery_codes where user_id = $1")
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await?;
+
+    // A login waiting for a code would have no way to finish
+    sqlx::query("delete from login_challenges where user_id = $1")
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await?;
+
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+/// Accept a code from time `step`, unless that or a later step was used already
+pub async fn use_step(connection_pool: &SqlitePool, user_id: i32, step: i64) -> Result<bool> {
+    let result =
+        sqlx::query("update users set totp_last_step = $1 where id = $2 and totp_last_step < $1")
+            .bind(step)
+            .bind(user_id)
+            .execute(connection_pool)
+            .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+/// Use up a recovery code, `false` when it is unknown or was used already
+pub async fn use_recovery_code(
+    connection_pool: &SqlitePool,
+    user_id: i32,
+    code: &str,
+) -> Result<bool> {
+    let result = sqlx::query(
+        "update recovery_codes set used_at = unixepoch() where user_id = $1 and code_hash = $2 and used_at is null",
+    )
+    .bind(user_id)
+    .bind(hash_token(code))
+    .execute(connection_pool)
+    .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+pub async fn remaining_recovery_codes(connection_pool: &SqlitePool, user_id: i32) -> Result<i64> {
+    Ok(
+        sqlx::query("select count(*) from recovery_codes where user_id = $1 and used_at is null")
+            .bind(user_id)
+            .fetch_one(connection_pool)
+            .await?
+            .get(0),
+    )
+}
+
+#[derive(FromRow)]
+pub struct Challenge {
+    pub id: i32,
+    pub user_id: i32,
+    /// Whether "remember me" was ticked on the password step
+    pub remember: bool,
+}
+
+/// Half finished login, the returned token goes into a cookie
+pub async fn create_challenge(
+    connection_pool: &SqlitePool,
+    user_id: i32,
+    remember: bool,
+) -> Result<String> {
+    let token = generate_token();
+
+    sqlx::query("delete from login_challenges where expires_at <= unixepoch()")
+        .execute(connection_pool)
+        .await?;
+
+    sqlx::query(
+        "insert into login_challenges (user_id, token_hash, remember, expires_at) values ($1, $2, $3, unixepoch() + $4)",
+    )
+    .bind(user_id)
+    .bind(hash_token(&token))
+    .bind(remember)
+    .bind(CHALLENGE_LIFETIME)
+    .execute(connection_pool)
+    .await?;
+
+    Ok(token)
+}
+
+/// `None` once the challenge expired or ran out of attempts
+pub async fn get_challenge(connection_pool: &SqlitePool, token: &str) -> Result<Option<Challenge>> {
+    Ok(sqlx::query_as::<_, Challenge>(
+        "select id, user_id, remember from login_challenges where token_hash = $1 and expires_at > unixepoch() and attempts < $2",
+    )
+    .bind(hash_token(token))
+    .bind(MAX_CHALLENGE_ATTEMPTS)
+    .fetch_optional(connection_pool)
+    .await?)
+}
+
+pub async fn record_challenge_failure(
+    connection_pool: &SqlitePool,
+    challenge_id: i32,
+) -> Result<()> {
+    sqlx::query("update login_challenges set attempts = attempts + 1 where id = $1")
+        .bind(challenge_id)
+        .execute(connection_pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn delete_challenge(connection_pool: &SqlitePool, challenge_id: i32) -> Result<()> {
+    sqlx::query("delete from login_challenges where id = $1")
+        .bind(challenge_id)
+        .execute(connection_pool)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_pool;
+
+    #[tokio::test]
+    async fn codes_are_not_accepted_twice() {
+        let connection_pool = test_pool().await;
+        start_enrolment(&connection_pool, 1, "SECRET")
+            .await
+            .unwrap();
+        let recovery_codes = vec!["aaaaa-bbbbb".to_owned(), "ccccc-ddddd".to_owned()];
+        enable(&connection_pool, 1, 100, &recovery_codes)
+            .await
+            .unwrap();
+
+        assert!(get_state(&connection_pool, 1).await.unwrap().enabled);
+        // The enrolment code and anything older count as used
+        assert!(!use_step(&connection_pool, 1, 100).await.unwrap());
+        assert!(use_step(&connection_pool, 1, 101).await.unwrap());
+        assert!(!use_step(&connection_pool, 1, 101).await.unwrap());
+
+        assert!(use_recovery_code(&connection_pool, 1, "aaaaa-bbbbb")
+            .await
+            .unwrap());
+        assert!(!use_recovery_code(&connection_pool, 1, "aaaaa-bbbbb")
+            .await
+            .unwrap());
+        assert!(!use_recovery_code(&connection_pool, 2, "ccccc-ddddd")
+            .await
+            .unwrap());
+        assert_eq!(
+            remaining_recovery_codes(&connection_pool, 1).await.unwrap(),
+            1
+        );
+
+        disable(&connection_pool, 1).await.unwrap();
+        assert!(!get_state(&connection_pool, 1).await.unwrap().enabled);
+        assert_eq!(
+            remaining_recovery_codes(&connection_pool, 1).await.unwrap(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn challenges_run_out_of_attempts() {
+        let connection_pool = test_pool().await;
+        let token = create_challenge(&connection_pool, 1, true).await.unwrap();
+
+        let challenge = get_challenge(&connection_pool, &token)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(challenge.user_id, 1);
+        assert!(challenge.remember);
+
+        for _ in 0..MAX_CHALLENGE_ATTEMPTS {
+            record_challenge_failure(&connection_pool, challenge.id)
+                .await
+                .unwrap();
+        }
+        assert!(get_challenge(&connection_pool, &token)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn disabling_drops_pending_challenges() {
+        let connection_pool = test_pool().await;
+        start_enrolment(&connection_pool, 1, "SECRET")
+            .await
+            .unwrap();
+        enable(&connection_pool, 1, 100, &[]).await.unwrap();
+        let token = create_challenge(&connection_pool, 1, false).await.unwrap();
+
+        disable(&connection_pool, 1).await.unwrap();
+
+        assert!(!get_state(&connection_pool, 1).await.unwrap().enabled);
+        assert!(get_challenge(&connection_pool, &token)
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,171 @@
+use askama::Template;
+use axum::{
+    extract::Request,
+    http::{
+        header::{CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER},
+        StatusCode,
+    },
+    middleware::Next,
+    response::{Html, IntoResponse, Response},
+};
+
+use crate::extractors::is_htmx_request;
+
+pub type AppResult<T = Response> = Result<T, AppError>;
+
+/// Error returned from handlers.
+/// Anything convertible into `anyhow::Error` becomes `Internal`, so handlers can use `?`.
+#[derive(Debug)]
+pub enum AppError {
+    NotFound,
+    Forbidden,
+    /// The user has to confirm their email address first
+    Unverified,
+    /// The account was suspended by a moderator
+    Suspended,
+    /// The message is shown to the user
+    BadRequest(String),
+    /// Rate limited, retry after this many seconds
+    TooManyRequests(i64),
+    Internal(anyhow::Error),
+}
+
+impl<E: Into<anyhow::Error>> From<E> for AppError {
+    fn from(error: E) -> Self {
+        AppError::Internal(error.into())
+    }
+}
+
+/// Attached to error responses, `render_errors` turns it into a page or a toast
+#[derive(Clone)]
+struct ErrorReport {
+    message: String,
+    /// Only logged, never shown to the user
+    detail: Option<String>,
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let (status, report) = match self {
+            AppError::NotFound => (
+                StatusCode::NOT_FOUND,
+                ErrorReport {
+                    message: "Nothing here".to_owned(),
+                    detail: None,
+                },
+            ),
+            AppError::Forbidden => (
+                StatusCode::FORBIDDEN,
+                ErrorReport {
+                    message: "You are not allowed to do that".to_owned(),
+                    detail: None,
+                },
+            ),
+            AppError::Unverified => (
+                StatusCode::FORBIDDEN,
+                ErrorReport {
+                    message: "Confirm your email address first, we sent you a link".to_owned(),
+                    detail: None,
+                },
+            ),
+            AppError::Suspended => (
+                StatusCode::FORBIDDEN,
+                ErrorReport {
+                    message: "This account is suspended".to_owned(),
+                    detail: None,
+                },
+            ),
+            AppError::BadRequest(message) => (
+                StatusCode::BAD_REQUEST,
+                ErrorReport {
+                    message,
+                    detail: None,
+                },
+            ),
+            AppError::TooManyRequests(retry_after) => {
+                let wait = if retry_after < 60 {
+                    format!("{retry_after} seconds")
+                } else {
+                    format!("{} minutes", (retry_after + 59) / 60)
+                };
+                let report = ErrorReport {
+                    message: format!("Too many attempts, try again in {wait}"),
+                    detail: None,
+                };
+                let mut response = (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(RETRY_AFTER, retry_after)],
+                    report.message.clone(),
+                )
+                    .into_response();
+                response.extensions_mut().insert(report);
+                return response;
+            }
+            AppError::Internal(error) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorReport {
+                    message: "Something went wrong, please try again".to_owned(),
+                    detail: Some(format!("{error:#}")),
+                },
+            ),
+        };
+
+        // Plain text in case the response doesn't pass through `render_errors`
+        let mut response = (status, report.message.clone()).into_response();
+        response.extensions_mut().insert(report);
+        response
+    }
+}
+
+#[derive(Template)]
+#[template(path = "error.html")]
+struct ErrorPageTemplate<'a> {
+    status: StatusCode,
+    message: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "toast.html")]
+struct ToastTemplate<'a> {
+    message: &'a str,
+}
+
+/// Middleware which logs `AppError`s with the request they happened in and renders them:
+/// an error page for full page loads, a toast appended to `#toasts` for htmx requests
+pub async fn render_errors(request: Request, next: Next) -> Response {
+    let htmx = is_htmx_request(request.headers());
+    let method = request.method().clone();
+    let uri = request.uri().clone();
+
+    let response = next.run(request).await;
+    let Some(report) = response.extensions().get::<ErrorReport>().cloned() else {
+        return response;
+    };
+    let status = response.status();
+
+    match &report.detail {
+        Some(detail) => tracing::error!(%method, %uri, %status, "{detail}"),
+        None => tracing::debug!(%method, %uri, %status, "{}", report.message),
+    }
+
+    // Keep what the handler set, like cookies and `Retry-After`, only the body is replaced
+    let mut headers = response.headers().clone();
+    headers.remove(CONTENT_TYPE);
+    headers.remove(CONTENT_LENGTH);
+
+    if htmx {
+        headers.insert("HX-Retarget", "#toasts".parse().unwrap());
+        headers.insert("HX-Reswap", "beforeend".parse().unwrap());
+
+        let toast = ToastTemplate {
+            message: &report.message,
+        };
+        (status, headers, Html(toast.to_string())).into_response()
+    } else {
+        let page = ErrorPageTemplate {
+            status,
+            message: &report.message,
+        };
+        (status, headers, Html(page.to_string())).into_response()
+    }
+}
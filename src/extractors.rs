@@ -0,0 +1,165 @@
+use std::marker::PhantomData;
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header::LOCATION, request::Parts, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::CookieJar;
+use sqlx::SqlitePool;
+
+use crate::{
+    db::{self, Role, User},
+    error::AppError,
+    helpers::{get_session_token, remove_session_cookie},
+};
+
+pub const LOGIN_PAGE: &str = "/login-form";
+
+/// User of the current session, rejects the request when nobody is logged in.
+/// Handlers taking it can rely on the session cookie holding the token of a live session.
+pub struct CurrentUser(pub User);
+
+/// User of the current session, if any
+pub struct MaybeUser(pub Option<User>);
+
+/// User of the current session who confirmed their email address
+pub struct VerifiedUser(pub User);
+
+/// User of the current session whose role is at least `R::ROLE`, anyone else gets a 403
+pub struct RequireRole<R>(pub User, pub PhantomData<R>);
+
+/// Minimum role checked by `RequireRole`
+pub trait RoleRequirement {
+    const ROLE: Role;
+}
+
+pub struct Moderator;
+impl RoleRequirement for Moderator {
+    const ROLE: Role = Role::Moderator;
+}
+
+pub struct Admin;
+impl RoleRequirement for Admin {
+    const ROLE: Role = Role::Admin;
+}
+
+/// Cached in the request extensions so that the session is only looked up once per request
+#[derive(Clone)]
+struct ResolvedUser(Option<User>);
+
+pub enum AuthRejection {
+    /// htmx requests are sent to the login form with `HX-Redirect`, plain ones with a 303
+    Unauthenticated {
+        htmx: bool,
+        jar: CookieJar,
+    },
+    Internal(anyhow::Error),
+}
+
+impl IntoResponse for AuthRejection {
+    fn into_response(self) -> Response {
+        match self {
+            AuthRejection::Unauthenticated { htmx, jar } => {
+                // Whatever the cookie holds it doesn't point at a live session
+                let jar = remove_session_cookie(jar);
+
+                let mut headers = HeaderMap::new();
+                if htmx {
+                    headers.insert("HX-Redirect", LOGIN_PAGE.parse().unwrap());
+                    (StatusCode::UNAUTHORIZED, jar, headers).into_response()
+                } else {
+                    headers.insert(LOCATION, LOGIN_PAGE.parse().unwrap());
+                    (StatusCode::SEE_OTHER, jar, headers).into_response()
+                }
+            }
+            AuthRejection::Internal(error) => AppError::Internal(error).into_response(),
+        }
+    }
+}
+
+pub fn is_htmx_request(headers: &HeaderMap) -> bool {
+    headers.contains_key("HX-Request")
+}
+
+async fn resolve_user(parts: &mut Parts) -> Result<Option<User>, AuthRejection> {
+    if let Some(ResolvedUser(user)) = parts.extensions.get::<ResolvedUser>() {
+        return Ok(user.clone());
+    }
+
+    let connection_pool = parts
+        .extensions
+        .get::<SqlitePool>()
+        .ok_or_else(|| AuthRejection::Internal(anyhow::anyhow!("Connection pool is missing")))?;
+
+    let jar = CookieJar::from_headers(&parts.headers);
+    let user = match get_session_token(&jar) {
+        Some(session_token) => db::get_user_from_session(connection_pool, &session_token)
+            .await
+            .map_err(AuthRejection::Internal)?,
+        None => None,
+    };
+
+    parts.extensions.insert(ResolvedUser(user.clone()));
+
+    Ok(user)
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for MaybeUser {
+    type Rejection = AuthRejection;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(MaybeUser(resolve_user(parts).await?))
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for CurrentUser {
+    type Rejection = AuthRejection;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        match resolve_user(parts).await? {
+            Some(user) => Ok(CurrentUser(user)),
+            None => Err(AuthRejection::Unauthenticated {
+                htmx: is_htmx_request(&parts.headers),
+                jar: CookieJar::from_headers(&parts.headers),
+            }),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for VerifiedUser {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let CurrentUser(user) = CurrentUser::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+
+        if user.email_verified() {
+            Ok(VerifiedUser(user))
+        } else {
+            Err(AppError::Unverified.into_response())
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync, R: RoleRequirement> FromRequestParts<S> for RequireRole<R> {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let CurrentUser(user) = CurrentUser::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+
+        if user.role >= R::ROLE {
+            Ok(RequireRole(user, PhantomData))
+        } else {
+            Err(AppError::Forbidden.into_response())
+        }
+    }
+}
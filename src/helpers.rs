@@ -1,16 +1,90 @@
-use axum_extra::extract::CookieJar;
+use axum_extra::extract::{
+    cookie::{Cookie, SameSite},
+    CookieJar,
+};
+use sha2::{Digest, Sha256};
+use time::Duration;
+use uuid::Uuid;
 
-pub const SESSION_ID_COOKIE_KEY: &str = "session_id";
-pub fn get_session_id(jar: &CookieJar) -> Option<i32> {
-    let session_id_cookie = jar.get(SESSION_ID_COOKIE_KEY);
+use crate::db::{two_factor::CHALLENGE_LIFETIME, REMEMBERED_SESSION_LIFETIME};
 
-    if let Some(session_id) = session_id_cookie {
-        let parsed_session_id = session_id.value().parse::<i32>();
+/// Cookies are only sent over https when `COOKIE_SECURE=true`
+pub fn cookie_secure() -> bool {
+    std::env::var("COOKIE_SECURE").is_ok_and(|value| value == "true")
+}
+
+/// Where the app is served from according to `APP_URL`, without a trailing slash
+pub fn app_base_url() -> String {
+    let url = std::env::var("APP_URL").unwrap_or_else(|_| "http://localhost:3000".to_owned());
+    url.trim_end_matches('/').to_owned()
+}
 
-        if let Ok(session_id) = parsed_session_id {
-            return Some(session_id);
+/// `scheme://host[:port]` of `APP_URL`, as browsers send it in the `Origin` header
+pub fn app_origin() -> String {
+    let url = app_base_url();
+    match url.split_once("://") {
+        Some((scheme, rest)) => {
+            let authority = rest.split('/').next().unwrap_or_default();
+            format!("{scheme}://{authority}")
         }
+        None => url,
+    }
+}
+
+pub const SESSION_COOKIE_KEY: &str = "session";
+pub fn get_session_token(jar: &CookieJar) -> Option<String> {
+    jar.get(SESSION_COOKIE_KEY)
+        .map(|cookie| cookie.value().to_owned())
+        .filter(|token| !token.is_empty())
+}
+
+/// Session cookie which isn't readable from js.
+/// Only remembered sessions outlive the browser, the others get a cookie without `Max-Age`.
+pub fn session_cookie(token: String, remember: bool) -> Cookie<'static> {
+    let mut cookie = Cookie::build((SESSION_COOKIE_KEY, token))
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .secure(cookie_secure());
+    if remember {
+        cookie = cookie.max_age(Duration::seconds(REMEMBERED_SESSION_LIFETIME.absolute));
     }
 
-    None
+    cookie.build()
+}
+
+pub fn remove_session_cookie(jar: CookieJar) -> CookieJar {
+    jar.remove(Cookie::build(SESSION_COOKIE_KEY).path("/"))
+}
+
+pub const LOGIN_CHALLENGE_COOKIE_KEY: &str = "login_challenge";
+pub fn get_login_challenge_token(jar: &CookieJar) -> Option<String> {
+    jar.get(LOGIN_CHALLENGE_COOKIE_KEY)
+        .map(|cookie| cookie.value().to_owned())
+        .filter(|token| !token.is_empty())
+}
+
+/// Remembers who entered the right password while the second factor is pending
+pub fn login_challenge_cookie(token: String) -> Cookie<'static> {
+    Cookie::build((LOGIN_CHALLENGE_COOKIE_KEY, token))
+        .path("/")
+        .max_age(Duration::seconds(CHALLENGE_LIFETIME))
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .secure(cookie_secure())
+        .build()
+}
+
+pub fn remove_login_challenge_cookie(jar: CookieJar) -> CookieJar {
+    jar.remove(Cookie::build(LOGIN_CHALLENGE_COOKIE_KEY).path("/"))
+}
+
+/// Random token to hand out to the client
+pub fn generate_token() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// Tokens are only stored hashed, so a leaked database can't be used to hijack them
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
 }
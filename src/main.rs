@@ -1,12 +1,24 @@
 use anyhow::Result;
-use axum::{routing::get_service, Extension};
+use axum::{middleware, routing::get_service, Extension};
+use sqlx::SqlitePool;
+use std::{net::SocketAddr, time::Duration};
 use tower_http::services::ServeFile;
 
+mod csrf;
 mod db;
+mod error;
+mod extractors;
 mod helpers;
+mod mail;
+mod password;
 mod routes;
+mod signing;
+mod timestamp;
+mod totp;
 mod utils;
+mod validation;
 
+use db::admin::Promotion;
 use routes::setup_router;
 
 #[tokio::main]
@@ -16,18 +28,82 @@ async fn main() -> Result<()> {
         Err(error) => panic!("Failed to generate styles: {error}"),
     }
     dotenv::dotenv().ok();
+    tracing_subscriber::fmt::init();
 
     let connection_pool = db::init().await?;
+    let mailer = mail::from_env()?;
+
+    if let Ok(email) = std::env::var("ADMIN_EMAIL") {
+        match db::admin::promote_to_admin(&connection_pool, &email).await? {
+            Promotion::Promoted => {}
+            Promotion::Unverified => tracing::warn!(
+                "ADMIN_EMAIL {email} isn't confirmed yet, the account stays a regular user"
+            ),
+            Promotion::NoAccount => {
+                tracing::warn!("ADMIN_EMAIL {email} doesn't belong to any account yet")
+            }
+        }
+    }
+
+    // Forms from any other origin are rejected, make a wrong or missing APP_URL easy to spot
+    match std::env::var("APP_URL") {
+        Ok(_) => tracing::info!("Accepting forms from {}", helpers::app_origin()),
+        Err(_) => tracing::warn!(
+            "APP_URL is not set, only forms from {} are accepted",
+            helpers::app_origin()
+        ),
+    }
+
+    tokio::spawn(reap_expired_sessions(connection_pool.clone()));
+    tokio::spawn(reap_stale_rate_limits(connection_pool.clone()));
 
     let app = setup_router()
         .route(
             "/static/styles.css",
             get_service(ServeFile::new("static/tailwind-generated.css")),
         )
-        .layer(Extension(connection_pool));
+        .layer(middleware::from_fn(csrf::protect))
+        .layer(middleware::from_fn(error::render_errors))
+        .layer(Extension(connection_pool))
+        .layer(Extension(mailer));
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
 
     Ok(())
 }
+
+/// Periodically delete sessions that are past their idle or absolute timeout
+async fn reap_expired_sessions(connection_pool: SqlitePool) {
+    let mut interval = tokio::time::interval(Duration::from_secs(10 * 60));
+
+    loop {
+        interval.tick().await;
+
+        match db::delete_expired_sessions(&connection_pool).await {
+            Ok(0) => {}
+            Ok(deleted) => tracing::info!("Deleted {deleted} expired sessions"),
+            Err(error) => tracing::error!("Failed to delete expired sessions: {error:#}"),
+        }
+    }
+}
+
+/// Periodically delete attempt counters that no longer limit anything
+async fn reap_stale_rate_limits(connection_pool: SqlitePool) {
+    let mut interval = tokio::time::interval(Duration::from_secs(10 * 60));
+
+    loop {
+        interval.tick().await;
+
+        match db::rate_limits::delete_stale(&connection_pool).await {
+            Ok(0) => {}
+            Ok(deleted) => tracing::info!("Deleted {deleted} stale rate limits"),
+            Err(error) => tracing::error!("Failed to delete stale rate limits: {error:#}"),
+        }
+    }
+}
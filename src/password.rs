@@ -0,0 +1,80 @@
+use anyhow::{anyhow, Result};
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use rand::rngs::OsRng;
+use std::sync::OnceLock;
+
+/// Hash a password with Argon2id and a random salt.
+/// The result is a PHC string which embeds the algorithm, params and salt.
+/// Argon2 takes a while on purpose, so it runs on the blocking thread pool.
+pub async fn hash(password: &str) -> Result<String> {
+    let password = password.to_owned();
+    tokio::task::spawn_blocking(move || hash_blocking(&password)).await?
+}
+
+fn hash_blocking(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|error| anyhow!("Failed to hash password: {error}"))
+}
+
+pub enum Verification {
+    Valid,
+    /// The stored value is a legacy plaintext password which matched,
+    /// it should be replaced with a proper hash
+    ValidLegacy,
+    Invalid,
+}
+
+/// Check `password` against a value from `users.password`.
+/// Rows created before hashing was introduced still hold plaintext,
+/// those are compared in constant time and reported as `ValidLegacy`.
+pub async fn verify(password: &str, stored: &str) -> Result<Verification> {
+    let (password, stored) = (password.to_owned(), stored.to_owned());
+    Ok(tokio::task::spawn_blocking(move || verify_blocking(&password, &stored)).await?)
+}
+
+fn verify_blocking(password: &str, stored: &str) -> Verification {
+    match PasswordHash::new(stored) {
+        Ok(hash) => match Argon2::default().verify_password(password.as_bytes(), &hash) {
+            Ok(()) => Verification::Valid,
+            Err(_) => Verification::Invalid,
+        },
+        Err(_) => {
+            if constant_time_eq(password.as_bytes(), stored.as_bytes()) {
+                Verification::ValidLegacy
+            } else {
+                Verification::Invalid
+            }
+        }
+    }
+}
+
+/// Burn the same time `verify` takes on a real hash, for logins with an unknown email.
+/// Otherwise the response time would tell which emails are registered.
+pub async fn verify_dummy(password: &str) -> Result<()> {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+
+    let password = password.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let dummy_hash =
+            DUMMY_HASH.get_or_init(|| hash_blocking("dummy password").unwrap_or_default());
+        let _ = verify_blocking(&password, dummy_hash);
+    })
+    .await?;
+
+    Ok(())
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -1,67 +1,94 @@
+mod admin;
 mod auth;
+mod password_reset;
 mod posts;
+mod reports;
+mod settings;
+mod two_factor;
+mod users;
+mod verification;
+use admin::setup_admin_router;
 use askama::Template;
 use auth::setup_auth_router;
-use axum::{
-    http::StatusCode,
-    response::{IntoResponse, Response},
-    routing::get,
-    Extension, Router,
-};
+use axum::{response::IntoResponse, routing::get, Extension, Router};
 use axum_extra::{extract::CookieJar, response::Html};
-use posts::setup_posts_router;
+use password_reset::setup_password_reset_router;
+use posts::{setup_posts_router, FeedTab};
+use reports::setup_reports_router;
+use settings::setup_settings_router;
 use sqlx::SqlitePool;
+use two_factor::setup_two_factor_router;
+use users::setup_users_router;
+use verification::setup_verification_router;
 
 use crate::{
-    db::{self, posts::Post, User},
-    helpers::{get_session_id, SESSION_ID_COOKIE_KEY},
+    csrf::CsrfToken,
+    db::{
+        self,
+        posts::{Cursor, Feed, Post},
+    },
+    error::AppResult,
+    extractors::MaybeUser,
+    helpers::remove_session_cookie,
 };
 
 pub fn setup_router() -> Router {
     Router::new()
         .route("/", get(index))
+        .merge(setup_admin_router())
         .merge(setup_auth_router())
+        .merge(setup_password_reset_router())
         .merge(setup_posts_router())
+        .merge(setup_reports_router())
+        .merge(setup_settings_router())
+        .merge(setup_two_factor_router())
+        .merge(setup_users_router())
+        .merge(setup_verification_router())
 }
 
 #[derive(Template)]
 #[template(path = "index.html")]
 struct IndexTemplate<'a> {
+    csrf_token: String,
     user_name: Option<&'a str>,
+    email_verified: bool,
     posts: Vec<Post>,
+    next_cursor: Option<Cursor>,
+    feed_url: String,
 }
-async fn index(jar: CookieJar, Extension(connection_pool): Extension<SqlitePool>) -> Response {
-    let user: Option<User> = match get_session_id(&jar) {
-        Some(session_id) => match db::get_user_from_session(&connection_pool, session_id).await {
-            Ok(user) => user,
-            Err(error) => {
-                dbg!(error);
-                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
-            }
-        },
-        None => None,
-    };
-
+async fn index(
+    CsrfToken(csrf_token): CsrfToken,
+    jar: CookieJar,
+    MaybeUser(user): MaybeUser,
+    Extension(connection_pool): Extension<SqlitePool>,
+) -> AppResult {
     let user_id = user.as_ref().map(|u| u.id);
-    let posts = match db::posts::get_all(&connection_pool, user_id).await {
-        Ok(posts) => posts,
-        Err(error) => {
-            dbg!(error);
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
-        }
-    };
+    let page = db::posts::get_page(
+        &connection_pool,
+        user_id,
+        user.as_ref().is_some_and(|u| u.is_moderator()),
+        Feed::Global,
+        None,
+        db::posts::DEFAULT_PAGE_SIZE,
+    )
+    .await?;
 
+    let email_verified = user.as_ref().is_some_and(|u| u.email_verified());
     let user_name = user.map(|u| u.name);
     let template = IndexTemplate {
+        email_verified,
+        csrf_token,
         user_name: user_name.as_deref(),
-        posts,
+        posts: page.posts,
+        next_cursor: page.next_cursor,
+        feed_url: FeedTab::Global.url().to_owned(),
     };
 
     let html_response = template.to_string();
 
     if user_name.is_some() {
-        axum::response::Html(html_response).into_response()
+        Ok(axum::response::Html(html_response).into_response())
     } else {
-        (jar.remove(SESSION_ID_COOKIE_KEY), Html(html_response)).into_response()
+        Ok((remove_session_cookie(jar), Html(html_response)).into_response())
     }
 }
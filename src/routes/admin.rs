@@ -0,0 +1,228 @@
+use askama::Template;
+use axum::{
+    extract::{Path, Query},
+    middleware,
+    response::{Html, IntoResponse},
+    routing::{delete, get, post, put},
+    Extension, Form, Router,
+};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+
+use crate::{
+    csrf::CsrfToken,
+    db::{
+        self,
+        admin::{Account, ActiveSession, PostSummary},
+        Role,
+    },
+    error::{AppError, AppResult},
+    extractors::{Admin, Moderator, RequireRole},
+};
+
+/// Everything under `/admin` needs at least a moderator, some actions an admin
+pub fn setup_admin_router() -> Router {
+    Router::new()
+        .route("/admin", get(dashboard))
+        .route("/admin/users", get(search_users))
+        .route(
+            "/admin/users/:user_id/suspension",
+            post(suspend_user).delete(unsuspend_user),
+        )
+        .route("/admin/users/:user_id/role", put(update_role))
+        .route("/admin/posts", get(search_posts))
+        .route("/admin/posts/:post_id", delete(delete_post))
+        .route("/admin/sessions", get(search_sessions))
+        .route("/admin/sessions/:session_id", delete(revoke_session))
+        .route_layer(middleware::from_extractor::<RequireRole<Moderator>>())
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    #[serde(default)]
+    q: String,
+}
+
+#[derive(Template)]
+#[template(path = "admin/index.html")]
+struct DashboardTemplate<'a> {
+    csrf_token: String,
+    user_name: Option<&'a str>,
+    viewer_role: Role,
+    users: Vec<Account>,
+    posts: Vec<PostSummary>,
+    /// Only shown to admins
+    sessions: Vec<ActiveSession>,
+}
+async fn dashboard(
+    CsrfToken(csrf_token): CsrfToken,
+    RequireRole(user, _): RequireRole<Moderator>,
+    Extension(connection_pool): Extension<SqlitePool>,
+) -> AppResult {
+    let sessions = if user.role >= Role::Admin {
+        db::admin::search_sessions(&connection_pool, "").await?
+    } else {
+        Vec::new()
+    };
+
+    let template = DashboardTemplate {
+        csrf_token,
+        user_name: Some(&user.name),
+        viewer_role: user.role,
+        users: db::admin::search_accounts(&connection_pool, "").await?,
+        posts: db::admin::search_posts(&connection_pool, "").await?,
+        sessions,
+    };
+
+    Ok(Html(template.to_string()).into_response())
+}
+
+#[derive(Template)]
+#[template(path = "admin/users.html")]
+struct UsersTemplate {
+    viewer_role: Role,
+    users: Vec<Account>,
+}
+async fn search_users(
+    RequireRole(user, _): RequireRole<Moderator>,
+    Extension(connection_pool): Extension<SqlitePool>,
+    Query(query): Query<SearchQuery>,
+) -> AppResult {
+    let template = UsersTemplate {
+        viewer_role: user.role,
+        users: db::admin::search_accounts(&connection_pool, query.q.trim()).await?,
+    };
+
+    Ok(Html(template.to_string()).into_response())
+}
+
+#[derive(Template)]
+#[template(path = "admin/user-row.html")]
+struct UserRowTemplate {
+    viewer_role: Role,
+    account: Account,
+}
+impl UserRowTemplate {
+    async fn render(connection_pool: &SqlitePool, viewer_role: Role, user_id: i32) -> AppResult {
+        let account = db::admin::get_account(connection_pool, user_id)
+            .await?
+            .ok_or(AppError::NotFound)?;
+
+        Ok(Html(
+            UserRowTemplate {
+                viewer_role,
+                account,
+            }
+            .to_string(),
+        )
+        .into_response())
+    }
+}
+
+/// Accounts can only be managed by someone with a higher role, which also rules out oneself
+async fn ensure_outranks(
+    connection_pool: &SqlitePool,
+    viewer_role: Role,
+    user_id: i32,
+) -> AppResult<()> {
+    let account = db::admin::get_account(connection_pool, user_id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    if account.role < viewer_role {
+        Ok(())
+    } else {
+        Err(AppError::Forbidden)
+    }
+}
+
+async fn suspend_user(
+    RequireRole(user, _): RequireRole<Moderator>,
+    Path(user_id): Path<i32>,
+    Extension(connection_pool): Extension<SqlitePool>,
+) -> AppResult {
+    ensure_outranks(&connection_pool, user.role, user_id).await?;
+    db::admin::suspend(&connection_pool, user_id).await?;
+
+    UserRowTemplate::render(&connection_pool, user.role, user_id).await
+}
+
+async fn unsuspend_user(
+    RequireRole(user, _): RequireRole<Moderator>,
+    Path(user_id): Path<i32>,
+    Extension(connection_pool): Extension<SqlitePool>,
+) -> AppResult {
+    ensure_outranks(&connection_pool, user.role, user_id).await?;
+    db::admin::unsuspend(&connection_pool, user_id).await?;
+
+    UserRowTemplate::render(&connection_pool, user.role, user_id).await
+}
+
+#[derive(Deserialize)]
+struct RoleForm {
+    role: Role,
+}
+async fn update_role(
+    RequireRole(user, _): RequireRole<Admin>,
+    Path(user_id): Path<i32>,
+    Extension(connection_pool): Extension<SqlitePool>,
+    Form(form): Form<RoleForm>,
+) -> AppResult {
+    ensure_outranks(&connection_pool, user.role, user_id).await?;
+    db::admin::set_role(&connection_pool, user_id, form.role).await?;
+
+    UserRowTemplate::render(&connection_pool, user.role, user_id).await
+}
+
+#[derive(Template)]
+#[template(path = "admin/posts.html")]
+struct PostsTemplate {
+    posts: Vec<PostSummary>,
+}
+async fn search_posts(
+    Extension(connection_pool): Extension<SqlitePool>,
+    Query(query): Query<SearchQuery>,
+) -> AppResult {
+    let posts = db::admin::search_posts(&connection_pool, query.q.trim()).await?;
+
+    Ok(Html(PostsTemplate { posts }.to_string()).into_response())
+}
+
+/// Responds with an empty body so that htmx removes the row
+async fn delete_post(
+    Path(post_id): Path<i32>,
+    Extension(connection_pool): Extension<SqlitePool>,
+) -> AppResult {
+    if !db::posts::exists(&connection_pool, true, post_id).await? {
+        return Err(AppError::NotFound);
+    }
+    db::posts::delete_post(&connection_pool, post_id).await?;
+
+    Ok(Html("").into_response())
+}
+
+#[derive(Template)]
+#[template(path = "admin/sessions.html")]
+struct SessionsTemplate {
+    sessions: Vec<ActiveSession>,
+}
+/// Sessions show where people log in from, so they are for admins only
+async fn search_sessions(
+    _: RequireRole<Admin>,
+    Extension(connection_pool): Extension<SqlitePool>,
+    Query(query): Query<SearchQuery>,
+) -> AppResult {
+    let sessions = db::admin::search_sessions(&connection_pool, query.q.trim()).await?;
+
+    Ok(Html(SessionsTemplate { sessions }.to_string()).into_response())
+}
+
+async fn revoke_session(
+    _: RequireRole<Admin>,
+    Path(session_id): Path<i32>,
+    Extension(connection_pool): Extension<SqlitePool>,
+) -> AppResult {
+    db::admin::delete_session(&connection_pool, session_id).await?;
+
+    Ok(Html("").into_response())
+}
@@ -1,19 +1,27 @@
 use askama::Template;
 use axum::{
-    http::StatusCode,
+    extract::ConnectInfo,
+    http::header::USER_AGENT,
     response::{Html, IntoResponse, Response},
     routing::{get, post},
     Extension, Form, Router,
 };
-use axum_extra::extract::cookie::{Cookie, CookieJar};
+use axum_extra::extract::cookie::CookieJar;
 use hyper::HeaderMap;
 use serde::Deserialize;
 use sqlx::SqlitePool;
-use std::fs;
+use std::net::SocketAddr;
 
+use super::{two_factor::start_two_factor_challenge, verification::send_verification_mail};
 use crate::{
-    db,
-    helpers::{self, SESSION_ID_COOKIE_KEY},
+    csrf::CsrfToken,
+    db::{self, rate_limits},
+    error::{AppError, AppResult},
+    extractors::is_htmx_request,
+    helpers,
+    mail::SharedMailer,
+    password::{self, Verification},
+    validation,
 };
 
 pub fn setup_auth_router() -> Router {
@@ -24,90 +32,257 @@ pub fn setup_auth_router() -> Router {
         .route("/register-form", get(register_form))
         .route("/logout", post(logout))
         .route("/email/registered", post(check_email_registered))
+        .route("/email/available", post(check_email_available))
 }
 
-async fn logout(Extension(connection_pool): Extension<SqlitePool>, jar: CookieJar) -> Response {
-    let session_id = helpers::get_session_id(&jar);
-    if session_id.is_none() {
-        return StatusCode::BAD_REQUEST.into_response();
-    }
-    let session_id = session_id.unwrap();
+async fn logout(Extension(connection_pool): Extension<SqlitePool>, jar: CookieJar) -> AppResult {
+    let session_token = helpers::get_session_token(&jar)
+        .ok_or_else(|| AppError::BadRequest("You are not logged in".to_owned()))?;
 
-    match db::delete_session_by_id(&connection_pool, session_id).await {
-        Ok(()) => {
-            let mut headers = HeaderMap::new();
-            headers.insert("HX-Refresh", "true".parse().unwrap());
+    db::delete_session_by_token(&connection_pool, &session_token).await?;
 
-            return (headers, jar.remove(SESSION_ID_COOKIE_KEY)).into_response();
-        }
-        Err(error) => {
-            println!("{error}");
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
-        }
-    };
+    let mut headers = HeaderMap::new();
+    headers.insert("HX-Refresh", "true".parse().unwrap());
+
+    Ok((headers, helpers::remove_session_cookie(jar)).into_response())
 }
 
 #[derive(Deserialize)]
 struct LoginForm {
     email: String,
     password: String,
+    /// Checkbox, only present when checked
+    remember_me: Option<String>,
 }
 
-async fn check_email_registered(
-    Extension(connection_pool): Extension<SqlitePool>,
-    Form(form): Form<LoginForm>,
-) -> Response {
-    match db::check_email_exists(&connection_pool, &form.email).await {
-        Ok(exists) => {
-            let html = if exists {
-                fs::read_to_string("templates/login-form/email-input-valid.html")
-                    .expect("what the hell")
-            } else {
-                fs::read_to_string("templates/login-form/email-input-invalid.html")
-                    .expect("what the hell")
-            };
-            println!("{html}");
-            Html(html).into_response()
+#[derive(Template)]
+#[template(path = "login-form/email-input-valid.html")]
+struct EmailRegisteredTemplate {
+    email: String,
+}
+
+#[derive(Template)]
+#[template(path = "login-form/email-input-invalid.html")]
+struct EmailNotRegisteredTemplate {
+    email: String,
+}
+
+/// Counts an attempt under each key before it is made.
+/// Rejects with `TooManyRequests` at the first key which is locked out, leaving the rest uncounted.
+pub async fn count_attempt(
+    connection_pool: &SqlitePool,
+    keys: &[(&str, &rate_limits::Limit)],
+) -> AppResult<()> {
+    for (key, limit) in keys {
+        if let Some(retry_after) = rate_limits::record_attempt(connection_pool, key, limit).await? {
+            return Err(AppError::TooManyRequests(retry_after));
         }
-        Err(error) => {
-            println!("{error}");
-            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+
+    Ok(())
+}
+
+/// Failed logins of an account are counted under this key, whatever the email's case
+fn login_account_key(email: &str) -> String {
+    format!("login-account:{}", email.trim().to_lowercase())
+}
+
+/// Check the password of someone who is logged in already, before a sensitive change.
+/// Failures count towards the same lockout as failed logins of the account.
+pub async fn confirm_password(
+    connection_pool: &SqlitePool,
+    user: &db::User,
+    password: &str,
+) -> AppResult<bool> {
+    let account_key = login_account_key(&user.email);
+    count_attempt(
+        connection_pool,
+        &[(&account_key, &rate_limits::LOGIN_ACCOUNT_LIMIT)],
+    )
+    .await?;
+
+    let credentials = db::get_credentials_by_id(connection_pool, user.id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    match password::verify(password, &credentials.password).await? {
+        Verification::Valid | Verification::ValidLegacy => {
+            rate_limits::clear(connection_pool, &account_key).await?;
+            Ok(true)
         }
+        Verification::Invalid => Ok(false),
     }
 }
 
+/// Anything telling whether an email is registered is rate limited per IP,
+/// so that it can't be used to enumerate accounts.
+/// `kind` keeps live checks and submissions on separate counters.
+async fn limit_email_probe(
+    connection_pool: &SqlitePool,
+    address: SocketAddr,
+    kind: &str,
+    limit: &rate_limits::Limit,
+) -> AppResult<()> {
+    let key = format!("{kind}:{}", address.ip());
+
+    count_attempt(connection_pool, &[(&key, limit)]).await
+}
+
+async fn check_email_registered(
+    Extension(connection_pool): Extension<SqlitePool>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Form(form): Form<EmailForm>,
+) -> AppResult {
+    limit_email_probe(
+        &connection_pool,
+        address,
+        "email-check",
+        &rate_limits::EMAIL_CHECK_LIMIT,
+    )
+    .await?;
+
+    let html = if db::check_email_exists(&connection_pool, &form.email).await? {
+        EmailRegisteredTemplate { email: form.email }.to_string()
+    } else {
+        EmailNotRegisteredTemplate { email: form.email }.to_string()
+    };
+
+    Ok(Html(html).into_response())
+}
+
+/// Wrong credentials re-render the form with an error and the email filled in.
+/// Unknown emails and wrong passwords take the same time and get the same message.
+/// Attempts are counted per IP and per account before the password is checked,
+/// both get locked out for a while when failures pile up.
 async fn login(
     Extension(connection_pool): Extension<SqlitePool>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     jar: CookieJar,
     Form(login_form): Form<LoginForm>,
-) -> impl IntoResponse {
-    let user_id =
-        db::get_user_id_from_login(&connection_pool, &login_form.email, &login_form.password).await;
-
-    match user_id {
-        Ok(user_id) => {
-            if let Some(user_id) = user_id {
-                dbg!(user_id);
-                if let Ok(session_id) = db::create_session(&connection_pool, user_id).await {
-                    let mut headers = HeaderMap::new();
-                    headers.insert("HX-Refresh", "true".parse().unwrap());
-                    return (
-                        jar.add(Cookie::new("session_id", session_id.to_string())),
-                        headers,
-                    )
-                        .into_response();
-                }
-            }
+) -> AppResult {
+    let ip_key = format!("login-ip:{}", address.ip());
+    let account_key = login_account_key(&login_form.email);
+    count_attempt(
+        &connection_pool,
+        &[
+            (&ip_key, &rate_limits::LOGIN_IP_LIMIT),
+            (&account_key, &rate_limits::LOGIN_ACCOUNT_LIMIT),
+        ],
+    )
+    .await?;
+
+    let credentials = db::get_credentials(&connection_pool, &login_form.email).await?;
+
+    let verification = match &credentials {
+        Some(credentials) => password::verify(&login_form.password, &credentials.password).await?,
+        None => {
+            password::verify_dummy(&login_form.password).await?;
+            Verification::Invalid
+        }
+    };
+
+    let credentials = match (credentials, verification) {
+        (Some(credentials), Verification::Valid) => credentials,
+        (Some(credentials), Verification::ValidLegacy) => {
+            // Rehash plaintext passwords left over from before hashing was introduced
+            let password_hash = password::hash(&login_form.password).await?;
+            db::update_password_hash(&connection_pool, credentials.id, &password_hash).await?;
+            credentials
         }
-        Err(error) => {
-            println!("{error}");
+        _ => {
+            let template = LoginFormTemplate {
+                email: login_form.email,
+                login_failed: true,
+            };
+            return Ok(Html(template.to_string()).into_response());
         }
+    };
+
+    // Only failures count towards the limits
+    rate_limits::clear(&connection_pool, &account_key).await?;
+    rate_limits::refund_attempt(&connection_pool, &ip_key).await?;
+
+    // Only revealed to someone who knows the password
+    if credentials.suspended {
+        return Err(AppError::Suspended);
+    }
+
+    let user_id = credentials.id;
+    let remember = login_form.remember_me.is_some();
+    if db::two_factor::get_state(&connection_pool, user_id)
+        .await?
+        .enabled
+    {
+        return start_two_factor_challenge(&connection_pool, jar, user_id, remember).await;
     }
-    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+
+    start_session(&connection_pool, jar, &headers, address, user_id, remember).await
+}
+
+/// Log the user in for good and reload the page
+pub async fn start_session(
+    connection_pool: &SqlitePool,
+    jar: CookieJar,
+    headers: &HeaderMap,
+    address: SocketAddr,
+    user_id: i32,
+    remember: bool,
+) -> AppResult {
+    let lifetime = if remember {
+        &db::REMEMBERED_SESSION_LIFETIME
+    } else {
+        &db::DEFAULT_SESSION_LIFETIME
+    };
+
+    let user_agent = headers
+        .get(USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok());
+    let ip_address = address.ip().to_string();
+
+    let session_token = db::create_session(
+        connection_pool,
+        user_id,
+        lifetime,
+        user_agent,
+        Some(&ip_address),
+    )
+    .await?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert("HX-Refresh", "true".parse().unwrap());
+    Ok((
+        jar.add(helpers::session_cookie(session_token, remember)),
+        headers,
+    )
+        .into_response())
+}
+
+#[derive(Template)]
+#[template(path = "login-form/index.html")]
+pub struct LoginFormTemplate {
+    pub email: String,
+    pub login_failed: bool,
+}
+
+#[derive(Template)]
+#[template(path = "login.html")]
+struct LoginPageTemplate {
+    csrf_token: String,
 }
 
-async fn login_form() -> Response {
-    return Html(fs::read_to_string("templates/login-form/index.html").unwrap()).into_response();
+/// Fragment for htmx swaps, a full page otherwise
+/// (unauthenticated requests get redirected here)
+async fn login_form(CsrfToken(csrf_token): CsrfToken, headers: HeaderMap) -> Response {
+    if is_htmx_request(&headers) {
+        let template = LoginFormTemplate {
+            email: String::new(),
+            login_failed: false,
+        };
+        Html(template.to_string()).into_response()
+    } else {
+        Html(LoginPageTemplate { csrf_token }.to_string()).into_response()
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -116,43 +291,136 @@ struct RegisterForm {
     email: String,
     password: String,
 }
+
+/// Values and per-field errors of the registration form
+#[derive(Default)]
+struct RegisterFields {
+    name: String,
+    name_error: Option<String>,
+    email: String,
+    email_error: Option<String>,
+    password_error: Option<String>,
+}
+
+pub const EMAIL_TAKEN: &str = "This email is already registered";
+
+/// Normalized email, or why it can't be used for a new account
+async fn validate_new_email(
+    connection_pool: &SqlitePool,
+    email: &str,
+) -> AppResult<Result<String, String>> {
+    let email = match validation::email(email) {
+        Ok(email) => email,
+        Err(message) => return Ok(Err(message)),
+    };
+    if db::check_email_exists(connection_pool, &email).await? {
+        return Ok(Err(EMAIL_TAKEN.to_owned()));
+    }
+    Ok(Ok(email))
+}
+
+#[derive(Template)]
+#[template(path = "register-form/form.html")]
+struct RegisterFormFragmentTemplate {
+    fields: RegisterFields,
+}
+/// Invalid input re-renders the form with the errors next to their fields
+/// New accounts get a verification mail, they can't post until it is confirmed
 async fn register(
     Extension(connection_pool): Extension<SqlitePool>,
+    Extension(mailer): Extension<SharedMailer>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
     Form(register_form): Form<RegisterForm>,
-) -> Response {
-    let email_exists_result = db::check_email_exists(&connection_pool, &register_form.email).await;
-
-    if let Ok(email_exists) = email_exists_result {
-        if email_exists {
-            return (StatusCode::BAD_REQUEST).into_response();
-        } else {
-            if db::create_user(
-                &connection_pool,
-                &register_form.email,
-                &register_form.name,
-                &register_form.password,
-            )
-            .await
-            .is_ok()
-            {
-                let mut headers = HeaderMap::new();
-                headers.insert("HX-Redirect", "/".parse().unwrap());
-                return headers.into_response();
-            } else {
-                return (StatusCode::INTERNAL_SERVER_ERROR).into_response();
-            }
-        }
-    } else {
-        return (StatusCode::INTERNAL_SERVER_ERROR).into_response();
-    }
+) -> AppResult {
+    // Tells whether the email is taken, just like the live check
+    limit_email_probe(
+        &connection_pool,
+        address,
+        "email-probe",
+        &rate_limits::EMAIL_PROBE_LIMIT,
+    )
+    .await?;
+
+    let name = validation::name(&register_form.name);
+    let email = validate_new_email(&connection_pool, &register_form.email).await?;
+    let password = validation::new_password(&register_form.password);
+
+    let (Ok(name), Ok(email), Ok(password)) = (&name, &email, &password) else {
+        let fields = RegisterFields {
+            name: register_form.name.trim().to_owned(),
+            name_error: name.err(),
+            email: register_form.email.trim().to_owned(),
+            email_error: email.err(),
+            password_error: password.err(),
+        };
+        return Ok(Html(RegisterFormFragmentTemplate { fields }.to_string()).into_response());
+    };
+
+    let password_hash = password::hash(password).await?;
+    let Some(user_id) = db::create_user(&connection_pool, email, name, &password_hash).await?
+    else {
+        // Someone else registered the address since it was checked
+        let fields = RegisterFields {
+            name: name.to_string(),
+            email: email.clone(),
+            email_error: Some(EMAIL_TAKEN.to_owned()),
+            ..Default::default()
+        };
+        return Ok(Html(RegisterFormFragmentTemplate { fields }.to_string()).into_response());
+    };
+    send_verification_mail(mailer, user_id, email.to_string());
+
+    let mut headers = HeaderMap::new();
+    headers.insert("HX-Redirect", "/".parse().unwrap());
+    Ok(headers.into_response())
 }
 
 #[derive(Template)]
 #[template(path = "register-form.html")]
 struct RegisterFormTemplate<'a> {
+    csrf_token: String,
     user_name: Option<&'a str>,
+    fields: RegisterFields,
 }
-async fn register_form() -> Response {
-    let template = RegisterFormTemplate { user_name: None };
-    return Html(template.to_string()).into_response();
+async fn register_form(CsrfToken(csrf_token): CsrfToken) -> Response {
+    let template = RegisterFormTemplate {
+        csrf_token,
+        user_name: None,
+        fields: RegisterFields::default(),
+    };
+    Html(template.to_string()).into_response()
+}
+
+#[derive(Deserialize)]
+struct EmailForm {
+    email: String,
+}
+#[derive(Template)]
+#[template(path = "register-form/email-input.html")]
+struct EmailInputTemplate {
+    fields: RegisterFields,
+}
+/// Live check of the registration form's email field
+async fn check_email_available(
+    Extension(connection_pool): Extension<SqlitePool>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Form(form): Form<EmailForm>,
+) -> AppResult {
+    limit_email_probe(
+        &connection_pool,
+        address,
+        "email-check",
+        &rate_limits::EMAIL_CHECK_LIMIT,
+    )
+    .await?;
+
+    let fields = RegisterFields {
+        email: form.email.trim().to_owned(),
+        email_error: validate_new_email(&connection_pool, &form.email)
+            .await?
+            .err(),
+        ..Default::default()
+    };
+
+    Ok(Html(EmailInputTemplate { fields }.to_string()).into_response())
 }
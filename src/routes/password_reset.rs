@@ -0,0 +1,199 @@
+use askama::Template;
+use axum::{
+    extract::{ConnectInfo, Query},
+    response::{Html, IntoResponse},
+    routing::get,
+    Extension, Form, Router,
+};
+use hyper::HeaderMap;
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use std::net::SocketAddr;
+
+use super::auth::count_attempt;
+use crate::{
+    csrf::CsrfToken,
+    db::{self, password_resets, rate_limits},
+    error::AppResult,
+    mail::{app_url, Email, SharedMailer},
+    password, validation,
+};
+
+pub fn setup_password_reset_router() -> Router {
+    Router::new()
+        .route(
+            "/forgot-password",
+            get(forgot_password_page).post(request_reset),
+        )
+        .route(
+            "/reset-password",
+            get(reset_password_page).post(reset_password),
+        )
+}
+
+#[derive(Template)]
+#[template(path = "forgot-password.html")]
+struct ForgotPasswordTemplate {
+    csrf_token: String,
+    sent: bool,
+}
+async fn forgot_password_page(CsrfToken(csrf_token): CsrfToken) -> AppResult {
+    let template = ForgotPasswordTemplate {
+        csrf_token,
+        sent: false,
+    };
+
+    Ok(Html(template.to_string()).into_response())
+}
+
+#[derive(Deserialize)]
+struct ForgotPasswordForm {
+    email: String,
+}
+#[derive(Template)]
+#[template(path = "forgot-password-form.html")]
+struct ForgotPasswordFormTemplate {
+    sent: bool,
+}
+/// Responds the same whether or not the email is registered,
+/// and mails in the background so the timing doesn't tell either
+async fn request_reset(
+    Extension(connection_pool): Extension<SqlitePool>,
+    Extension(mailer): Extension<SharedMailer>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Form(form): Form<ForgotPasswordForm>,
+) -> AppResult {
+    let email = form.email.trim().to_owned();
+
+    let ip_key = format!("password-reset-ip:{}", address.ip());
+    let account_key = format!("password-reset-account:{}", email.to_lowercase());
+    count_attempt(
+        &connection_pool,
+        &[
+            (&ip_key, &rate_limits::PASSWORD_RESET_LIMIT),
+            (&account_key, &rate_limits::PASSWORD_RESET_LIMIT),
+        ],
+    )
+    .await?;
+
+    tokio::spawn(async move {
+        if let Err(error) = send_reset_mail(&connection_pool, &mailer, email).await {
+            tracing::error!("Failed to send password reset mail: {error:#}");
+        }
+    });
+
+    Ok(Html(ForgotPasswordFormTemplate { sent: true }.to_string()).into_response())
+}
+
+async fn send_reset_mail(
+    connection_pool: &SqlitePool,
+    mailer: &SharedMailer,
+    email: String,
+) -> anyhow::Result<()> {
+    let Some(credentials) = db::get_credentials(connection_pool, &email).await? else {
+        return Ok(());
+    };
+
+    let token = password_resets::create_reset(connection_pool, credentials.id).await?;
+    let link = app_url(&format!("/reset-password?token={token}"));
+
+    mailer
+        .send(Email {
+            to: email,
+            subject: "Reset your password".to_owned(),
+            body: format!(
+                "Someone asked to reset the password of your account.\n\n\
+                 Open this link within {} minutes to choose a new one:\n{link}\n\n\
+                 If it wasn't you, ignore this mail, your password stays the same.",
+                password_resets::RESET_TOKEN_LIFETIME / 60
+            ),
+        })
+        .await
+}
+
+#[derive(Deserialize)]
+struct ResetQuery {
+    token: String,
+}
+#[derive(Template)]
+#[template(path = "reset-password.html")]
+struct ResetPasswordTemplate {
+    csrf_token: String,
+    token: String,
+    /// The token is unknown, used or expired
+    expired: bool,
+    password_error: Option<String>,
+}
+async fn reset_password_page(
+    CsrfToken(csrf_token): CsrfToken,
+    Query(query): Query<ResetQuery>,
+    Extension(connection_pool): Extension<SqlitePool>,
+) -> AppResult {
+    let expired = !password_resets::is_valid(&connection_pool, &query.token).await?;
+
+    let template = ResetPasswordTemplate {
+        csrf_token,
+        token: query.token,
+        expired,
+        password_error: None,
+    };
+
+    Ok(Html(template.to_string()).into_response())
+}
+
+#[derive(Deserialize)]
+struct ResetPasswordForm {
+    token: String,
+    password: String,
+}
+#[derive(Template)]
+#[template(path = "reset-password-form.html")]
+struct ResetPasswordFormTemplate {
+    token: String,
+    expired: bool,
+    password_error: Option<String>,
+}
+/// Sends the user to the login form once the password is changed,
+/// every session of theirs is gone by then
+async fn reset_password(
+    Extension(connection_pool): Extension<SqlitePool>,
+    Form(form): Form<ResetPasswordForm>,
+) -> AppResult {
+    let password = match validation::new_password(&form.password) {
+        Ok(password) => password,
+        Err(message) => {
+            let template = ResetPasswordFormTemplate {
+                token: form.token,
+                expired: false,
+                password_error: Some(message),
+            };
+            return Ok(Html(template.to_string()).into_response());
+        }
+    };
+
+    let expired = || {
+        let template = ResetPasswordFormTemplate {
+            token: form.token.clone(),
+            expired: true,
+            password_error: None,
+        };
+        Ok(Html(template.to_string()).into_response())
+    };
+
+    // Anyone can post here, so unknown tokens are turned away before any Argon2 work
+    if !password_resets::is_valid(&connection_pool, &form.token).await? {
+        return expired();
+    }
+
+    let password_hash = password::hash(password).await?;
+    if password_resets::complete_reset(&connection_pool, &form.token, &password_hash)
+        .await?
+        .is_none()
+    {
+        return expired();
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert("HX-Redirect", "/login-form".parse().unwrap());
+    Ok(headers.into_response())
+}
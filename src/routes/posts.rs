@@ -1,23 +1,24 @@
 use askama::Template;
 use axum::{
-    extract::Path,
+    extract::{Path, Query},
     http::StatusCode,
-    response::{Html, IntoResponse, Response},
-    routing::{delete, get, post},
+    response::{Html, IntoResponse},
+    routing::{delete, get, post, put},
     Extension, Form, Router,
 };
-use axum_extra::extract::cookie::CookieJar;
 use hyper::HeaderMap;
 use serde::Deserialize;
 use sqlx::SqlitePool;
 
 use crate::{
+    csrf::CsrfToken,
     db::{
         self,
-        posts::{Comment, Post},
+        posts::{Comment, Cursor, Feed, Post},
         User,
     },
-    helpers::get_session_id,
+    error::{AppError, AppResult},
+    extractors::{CurrentUser, MaybeUser, VerifiedUser},
 };
 
 pub fn setup_posts_router() -> Router {
@@ -25,7 +26,12 @@ pub fn setup_posts_router() -> Router {
         .route("/posts", get(get_posts))
         .route("/posts/:post_id", get(get_one_post))
         .route("/posts/:post_id/comments", get(get_comments_by_post_id))
+        .route("/posts/:post_id/comments", post(create_comment))
         .route("/posts", post(create_post))
+        .route("/posts/:post_id", put(update_post))
+        .route("/posts/:post_id", delete(delete_post))
+        .route("/posts/:post_id/body", get(get_post_body))
+        .route("/posts/:post_id/edit", get(edit_post_form))
         .route("/likes/:post_id", post(like_post))
         .route("/likes/:post_id", delete(unlike_post))
 }
@@ -37,222 +43,361 @@ struct CommentsTemplate {
     post_id: i32,
 }
 async fn get_comments_by_post_id(
+    MaybeUser(user): MaybeUser,
     Path(post_id): Path<i32>,
     Extension(connection_pool): Extension<SqlitePool>,
-) -> Response {
-    let comments = match db::posts::comments(&connection_pool, post_id).await {
-        Ok(comments) => comments,
-        Err(error) => {
-            dbg!(error);
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
-        }
-    };
+) -> AppResult {
+    let include_hidden = user.is_some_and(|u| u.is_moderator());
+    if !db::posts::exists(&connection_pool, include_hidden, post_id).await? {
+        return Err(AppError::NotFound);
+    }
+    let comments = db::posts::comments(&connection_pool, post_id, include_hidden).await?;
 
     let template = CommentsTemplate { comments, post_id };
 
-    Html(template.to_string()).into_response()
+    Ok(Html(template.to_string()).into_response())
+}
+
+const MAX_COMMENT_LENGTH: usize = 1000;
+
+#[derive(Deserialize)]
+struct CommentForm {
+    body: String,
+}
+/// Fires `commentCreated` so the comments list refreshes itself
+async fn create_comment(
+    VerifiedUser(user): VerifiedUser,
+    Path(post_id): Path<i32>,
+    Extension(connection_pool): Extension<SqlitePool>,
+    Form(comment_form): Form<CommentForm>,
+) -> AppResult {
+    let body = comment_form.body.trim();
+    if body.is_empty() {
+        return Err(AppError::BadRequest("Comment can't be empty".to_owned()));
+    }
+    if body.chars().count() > MAX_COMMENT_LENGTH {
+        return Err(AppError::BadRequest(format!(
+            "Comment can't be longer than {MAX_COMMENT_LENGTH} characters"
+        )));
+    }
+
+    if !db::posts::exists(&connection_pool, user.is_moderator(), post_id).await? {
+        return Err(AppError::NotFound);
+    }
+
+    db::posts::create_comment(&connection_pool, post_id, user.id, body).await?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert("HX-Trigger", "commentCreated".parse().unwrap());
+
+    Ok((headers, StatusCode::CREATED).into_response())
 }
 
 #[derive(Template)]
 #[template(path = "post.html")]
 struct PostTemplate<'a> {
+    csrf_token: String,
     post: Post,
     user_name: Option<&'a str>,
+    email_verified: bool,
     // comments related
     post_id: i32,
     comments: Vec<Comment>,
 }
 async fn get_one_post(
-    jar: CookieJar,
+    CsrfToken(csrf_token): CsrfToken,
+    MaybeUser(user): MaybeUser,
     Path(post_id): Path<i32>,
     Extension(connection_pool): Extension<SqlitePool>,
-) -> Response {
-    let user: Option<User> = match get_session_id(&jar) {
-        Some(session_id) => match db::get_user_from_session(&connection_pool, session_id).await {
-            Ok(user) => user,
-            Err(error) => {
-                dbg!(error);
-                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
-            }
-        },
-        None => None,
-    };
+) -> AppResult {
     let user_id = user.as_ref().map(|u| u.id);
+    let include_hidden = user.as_ref().is_some_and(|u| u.is_moderator());
 
-    let post = match db::posts::get_by_id(&connection_pool, user_id, post_id).await {
-        Ok(post) => post,
-        Err(error) => {
-            dbg!(error);
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
-        }
-    };
-    let comments = match db::posts::comments(&connection_pool, post_id).await {
-        Ok(comments) => comments,
-        Err(error) => {
-            dbg!(error);
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
-        }
-    };
+    let post = db::posts::get_by_id(&connection_pool, user_id, include_hidden, post_id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    let comments = db::posts::comments(&connection_pool, post_id, include_hidden).await?;
 
+    let email_verified = user.as_ref().is_some_and(|u| u.email_verified());
     let user_name = user.map(|u| u.name);
 
     let post_template = PostTemplate {
+        email_verified,
+        csrf_token,
         post_id: post.id,
         post,
         comments,
         user_name: user_name.as_deref(),
     };
 
-    Html(post_template.to_string()).into_response()
+    Ok(Html(post_template.to_string()).into_response())
 }
 
 #[derive(Template)]
 #[template(path = "posts.html")]
 struct PostsTemplate {
+    /// Follow buttons are only shown to logged in users
+    logged_in: bool,
     posts: Vec<Post>,
+    next_cursor: Option<Cursor>,
+    feed_url: String,
 }
-async fn get_posts(jar: CookieJar, Extension(connection_pool): Extension<SqlitePool>) -> Response {
-    let user: Option<User> = match get_session_id(&jar) {
-        Some(session_id) => match db::get_user_from_session(&connection_pool, session_id).await {
-            Ok(user) => user,
-            Err(error) => {
-                dbg!(error);
-                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
-            }
-        },
-        None => None,
-    };
 
+/// Only the `<li>`s, appended by the infinite scroll loader
+#[derive(Template)]
+#[template(path = "posts-page.html")]
+struct PostsPageTemplate {
+    logged_in: bool,
+    posts: Vec<Post>,
+    next_cursor: Option<Cursor>,
+    feed_url: String,
+}
+
+/// Feed tab picked by the `feed` query parameter
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum FeedTab {
+    #[default]
+    Global,
+    Following,
+}
+impl FeedTab {
+    /// Where the list reloads itself and its pages from
+    pub fn url(self) -> &'static str {
+        match self {
+            FeedTab::Global => "/posts?feed=global",
+            FeedTab::Following => "/posts?feed=following",
+        }
+    }
+}
+
+/// Where a profile's list of posts reloads itself and its pages from
+pub fn author_feed_url(author_id: i32) -> String {
+    format!("/posts?author={author_id}")
+}
+
+#[derive(Deserialize)]
+struct PageQuery {
+    #[serde(default)]
+    feed: FeedTab,
+    /// Only posts by this user, takes precedence over `feed`
+    author: Option<i32>,
+    before: Option<String>,
+    limit: Option<i64>,
+}
+/// Without `before` renders the whole list with its first page,
+/// otherwise just the requested page
+async fn get_posts(
+    MaybeUser(user): MaybeUser,
+    Query(page_query): Query<PageQuery>,
+    Extension(connection_pool): Extension<SqlitePool>,
+) -> AppResult {
+    let include_hidden = user.as_ref().is_some_and(|u| u.is_moderator());
     let user_id = user.map(|u| u.id);
+    let before = page_query
+        .before
+        .map(|before| before.parse::<Cursor>())
+        .transpose()
+        .map_err(|_| AppError::BadRequest("Invalid page cursor".to_owned()))?;
+    let limit = page_query.limit.unwrap_or(db::posts::DEFAULT_PAGE_SIZE);
+    let feed = match (page_query.author, page_query.feed, user_id) {
+        (Some(author_id), _, _) => Feed::Author(author_id),
+        (None, FeedTab::Global, _) => Feed::Global,
+        (None, FeedTab::Following, Some(user_id)) => Feed::Following(user_id),
+        (None, FeedTab::Following, None) => {
+            return Err(AppError::BadRequest(
+                "Log in to see posts from people you follow".to_owned(),
+            ))
+        }
+    };
+    let feed_url = match page_query.author {
+        Some(author_id) => author_feed_url(author_id),
+        None => page_query.feed.url().to_owned(),
+    };
+
+    let page = db::posts::get_page(
+        &connection_pool,
+        user_id,
+        include_hidden,
+        feed,
+        before,
+        limit,
+    )
+    .await?;
 
-    let posts = match db::posts::get_all(&connection_pool, user_id).await {
-        Ok(posts) => posts,
-        Err(error) => {
-            println!("{error}");
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    let html = if before.is_some() {
+        PostsPageTemplate {
+            logged_in: user_id.is_some(),
+            posts: page.posts,
+            next_cursor: page.next_cursor,
+            feed_url,
         }
+        .to_string()
+    } else {
+        PostsTemplate {
+            logged_in: user_id.is_some(),
+            posts: page.posts,
+            next_cursor: page.next_cursor,
+            feed_url,
+        }
+        .to_string()
     };
-    let template = PostsTemplate { posts };
 
-    Html(template.to_string()).into_response()
+    Ok(Html(html).into_response())
 }
+const MAX_POST_LENGTH: usize = 2000;
+
 #[derive(Deserialize)]
 struct PostForm {
     body: String,
 }
+impl PostForm {
+    /// Trimmed body, or the reason it can't be posted
+    fn validated_body(&self) -> Result<&str, AppError> {
+        let body = self.body.trim();
+        if body.is_empty() {
+            return Err(AppError::BadRequest("Post can't be empty".to_owned()));
+        }
+        if body.chars().count() > MAX_POST_LENGTH {
+            return Err(AppError::BadRequest(format!(
+                "Post can't be longer than {MAX_POST_LENGTH} characters"
+            )));
+        }
+        Ok(body)
+    }
+}
 async fn create_post(
-    jar: CookieJar,
+    VerifiedUser(user): VerifiedUser,
     Extension(connection_pool): Extension<SqlitePool>,
     Form(post_form): Form<PostForm>,
-) -> Response {
-    let user_id: i32 = match get_session_id(&jar) {
-        Some(session_id) => match db::get_user_from_session(&connection_pool, session_id).await {
-            Ok(user) => match user {
-                Some(user) => user.id,
-                None => {
-                    return (
-                        jar.remove("session_id"),
-                        StatusCode::NETWORK_AUTHENTICATION_REQUIRED,
-                    )
-                        .into_response();
-                }
-            },
-            Err(error) => {
-                dbg!(error);
-                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
-            }
-        },
-        None => {
-            return StatusCode::NETWORK_AUTHENTICATION_REQUIRED.into_response();
-        }
-    };
+) -> AppResult {
+    let body = post_form.validated_body()?;
+    db::posts::create_post(&connection_pool, user.id, body).await?;
 
-    match db::posts::create_post(&connection_pool, user_id, &post_form.body).await {
-        Ok(_) => {
-            println!("Created a post: {}", post_form.body);
-            let mut headers = HeaderMap::new();
-            headers.insert("HX-Trigger", "postCreated".parse().unwrap());
+    let mut headers = HeaderMap::new();
+    headers.insert("HX-Trigger", "postCreated".parse().unwrap());
 
-            return (headers, StatusCode::CREATED).into_response();
-        }
-        Err(error) => {
-            println!("{error}");
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
-        }
+    Ok((headers, StatusCode::CREATED).into_response())
+}
+/// Only the author may change or delete a post
+async fn ensure_author(connection_pool: &SqlitePool, user: &User, post_id: i32) -> AppResult<()> {
+    match db::posts::get_author_id(connection_pool, user.is_moderator(), post_id).await? {
+        Some(author_id) if author_id == user.id => Ok(()),
+        Some(_) => Err(AppError::Forbidden),
+        None => Err(AppError::NotFound),
     }
 }
+
 #[derive(Template)]
-#[template(path = "like-button.html")]
-struct LikeButtonTemplate {
+#[template(path = "post-body.html")]
+struct PostBodyTemplate {
     post: Post,
 }
-async fn like_post(
-    jar: CookieJar,
+/// Rendered post body, used to cancel editing
+async fn get_post_body(
+    MaybeUser(user): MaybeUser,
     Path(post_id): Path<i32>,
     Extension(connection_pool): Extension<SqlitePool>,
-) -> Response {
-    let session_id = get_session_id(&jar);
-    if session_id.is_none() {
-        return StatusCode::UNAUTHORIZED.into_response();
-    }
-    let session_id = session_id.unwrap();
+) -> AppResult {
+    let include_hidden = user.as_ref().is_some_and(|u| u.is_moderator());
+    let user_id = user.map(|u| u.id);
+    let post = db::posts::get_by_id(&connection_pool, user_id, include_hidden, post_id)
+        .await?
+        .ok_or(AppError::NotFound)?;
 
-    let user = match db::get_user_from_session(&connection_pool, session_id).await {
-        Ok(user) => user,
-        Err(error) => {
-            dbg!(error);
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
-        }
-    };
+    Ok(Html(PostBodyTemplate { post }.to_string()).into_response())
+}
 
-    if user.is_none() {
-        return StatusCode::UNAUTHORIZED.into_response();
-    }
-    let user = user.unwrap();
+#[derive(Template)]
+#[template(path = "post-edit-form.html")]
+struct PostEditFormTemplate {
+    post: Post,
+}
+async fn edit_post_form(
+    CurrentUser(user): CurrentUser,
+    Path(post_id): Path<i32>,
+    Extension(connection_pool): Extension<SqlitePool>,
+) -> AppResult {
+    ensure_author(&connection_pool, &user, post_id).await?;
 
-    match db::posts::like_post(&connection_pool, user.id, post_id).await {
-        Ok(post) => {
-            let like_button_template = LikeButtonTemplate { post };
-            Html(like_button_template.to_string()).into_response()
-        }
-        Err(error) => {
-            dbg!(&error);
-            StatusCode::INTERNAL_SERVER_ERROR.into_response()
-        }
-    }
+    let post = db::posts::get_by_id(
+        &connection_pool,
+        Some(user.id),
+        user.is_moderator(),
+        post_id,
+    )
+    .await?
+    .ok_or(AppError::NotFound)?;
+
+    Ok(Html(PostEditFormTemplate { post }.to_string()).into_response())
 }
-async fn unlike_post(
-    jar: CookieJar,
+
+/// Responds with the rendered post body which replaces the edit form
+async fn update_post(
+    CurrentUser(user): CurrentUser,
     Path(post_id): Path<i32>,
     Extension(connection_pool): Extension<SqlitePool>,
-) -> Response {
-    let session_id = get_session_id(&jar);
-    if session_id.is_none() {
-        return StatusCode::UNAUTHORIZED.into_response();
-    }
-    let session_id = session_id.unwrap();
+    Form(post_form): Form<PostForm>,
+) -> AppResult {
+    ensure_author(&connection_pool, &user, post_id).await?;
+    let body = post_form.validated_body()?;
 
-    let user = match db::get_user_from_session(&connection_pool, session_id).await {
-        Ok(user) => user,
-        Err(error) => {
-            dbg!(error);
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
-        }
-    };
+    db::posts::update_post(&connection_pool, post_id, body).await?;
 
-    if user.is_none() {
-        return StatusCode::UNAUTHORIZED.into_response();
-    }
-    let user = user.unwrap();
+    let post = db::posts::get_by_id(
+        &connection_pool,
+        Some(user.id),
+        user.is_moderator(),
+        post_id,
+    )
+    .await?
+    .ok_or(AppError::NotFound)?;
 
-    match db::posts::remove_like(&connection_pool, user.id, post_id).await {
-        Ok(post) => {
-            let like_button_template = LikeButtonTemplate { post };
-            Html(like_button_template.to_string()).into_response()
-        }
-        Err(error) => {
-            dbg!(&error);
-            StatusCode::INTERNAL_SERVER_ERROR.into_response()
-        }
-    }
+    Ok(Html(PostBodyTemplate { post }.to_string()).into_response())
+}
+
+/// Responds with an empty body so htmx removes the post, and fires `postDeleted`
+async fn delete_post(
+    CurrentUser(user): CurrentUser,
+    Path(post_id): Path<i32>,
+    Extension(connection_pool): Extension<SqlitePool>,
+) -> AppResult {
+    ensure_author(&connection_pool, &user, post_id).await?;
+
+    db::posts::delete_post(&connection_pool, post_id).await?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert("HX-Trigger", "postDeleted".parse().unwrap());
+
+    Ok((headers, Html("")).into_response())
+}
+
+#[derive(Template)]
+#[template(path = "like-button.html")]
+struct LikeButtonTemplate {
+    post: Post,
+}
+async fn like_post(
+    CurrentUser(user): CurrentUser,
+    Path(post_id): Path<i32>,
+    Extension(connection_pool): Extension<SqlitePool>,
+) -> AppResult {
+    let post = db::posts::like_post(&connection_pool, user.id, user.is_moderator(), post_id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    let like_button_template = LikeButtonTemplate { post };
+
+    Ok(Html(like_button_template.to_string()).into_response())
+}
+async fn unlike_post(
+    CurrentUser(user): CurrentUser,
+    Path(post_id): Path<i32>,
+    Extension(connection_pool): Extension<SqlitePool>,
+) -> AppResult {
+    let post = db::posts::remove_like(&connection_pool, user.id, user.is_moderator(), post_id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    let like_button_template = LikeButtonTemplate { post };
+
+    Ok(Html(like_button_template.to_string()).into_response())
 }
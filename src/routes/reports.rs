@@ -0,0 +1,180 @@
+use askama::Template;
+use axum::{
+    extract::Path,
+    middleware,
+    response::{Html, IntoResponse},
+    routing::{get, post},
+    Extension, Form, Router,
+};
+use hyper::HeaderMap;
+use serde::Deserialize;
+use sqlx::SqlitePool;
+
+use crate::{
+    csrf::CsrfToken,
+    db::{
+        self,
+        reports::{AuditEntry, OpenReport, Outcome, Target},
+        Role,
+    },
+    error::{AppError, AppResult},
+    extractors::{Moderator, RequireRole, VerifiedUser},
+    validation,
+};
+
+/// Anyone who can post may report, the queue is for moderators
+pub fn setup_reports_router() -> Router {
+    let queue = Router::new()
+        .route("/admin/reports", get(report_queue))
+        .route("/admin/reports/:report_id", post(resolve_report))
+        .route("/admin/audit-log", get(get_audit_log))
+        .route_layer(middleware::from_extractor::<RequireRole<Moderator>>());
+
+    Router::new()
+        .route("/posts/:post_id/report", post(report_post))
+        .route("/comments/:comment_id/report", post(report_comment))
+        .merge(queue)
+}
+
+#[derive(Deserialize)]
+struct ReportForm {
+    reason: String,
+}
+#[derive(Template)]
+#[template(path = "report-sent.html")]
+struct ReportSentTemplate;
+
+async fn report(
+    connection_pool: &SqlitePool,
+    reporter_id: i32,
+    author_id: Option<i32>,
+    target: Target,
+    reason: &str,
+) -> AppResult {
+    let reason = validation::report_reason(reason).map_err(AppError::BadRequest)?;
+    match author_id {
+        None => return Err(AppError::NotFound),
+        Some(author_id) if author_id == reporter_id => {
+            return Err(AppError::BadRequest(
+                "You can't report what you wrote yourself".to_owned(),
+            ))
+        }
+        Some(_) => {}
+    }
+
+    db::reports::create_report(connection_pool, reporter_id, target, reason).await?;
+
+    Ok(Html(ReportSentTemplate.to_string()).into_response())
+}
+
+/// Replaces the report form with a thank you
+async fn report_post(
+    VerifiedUser(user): VerifiedUser,
+    Path(post_id): Path<i32>,
+    Extension(connection_pool): Extension<SqlitePool>,
+    Form(form): Form<ReportForm>,
+) -> AppResult {
+    let author_id =
+        db::posts::get_author_id(&connection_pool, user.is_moderator(), post_id).await?;
+
+    report(
+        &connection_pool,
+        user.id,
+        author_id,
+        Target::Post(post_id),
+        &form.reason,
+    )
+    .await
+}
+
+async fn report_comment(
+    VerifiedUser(user): VerifiedUser,
+    Path(comment_id): Path<i32>,
+    Extension(connection_pool): Extension<SqlitePool>,
+    Form(form): Form<ReportForm>,
+) -> AppResult {
+    let author_id =
+        db::posts::get_comment_author_id(&connection_pool, user.is_moderator(), comment_id).await?;
+
+    report(
+        &connection_pool,
+        user.id,
+        author_id,
+        Target::Comment(comment_id),
+        &form.reason,
+    )
+    .await
+}
+
+#[derive(Template)]
+#[template(path = "admin/reports.html")]
+struct ReportsPageTemplate<'a> {
+    csrf_token: String,
+    user_name: Option<&'a str>,
+    viewer_role: Role,
+    reports: Vec<OpenReport>,
+    audit_log: Vec<AuditEntry>,
+}
+async fn report_queue(
+    CsrfToken(csrf_token): CsrfToken,
+    RequireRole(user, _): RequireRole<Moderator>,
+    Extension(connection_pool): Extension<SqlitePool>,
+) -> AppResult {
+    let template = ReportsPageTemplate {
+        csrf_token,
+        user_name: Some(&user.name),
+        viewer_role: user.role,
+        reports: db::reports::open_reports(&connection_pool).await?,
+        audit_log: db::reports::audit_log(&connection_pool).await?,
+    };
+
+    Ok(Html(template.to_string()).into_response())
+}
+
+#[derive(Deserialize)]
+struct OutcomeForm {
+    outcome: Outcome,
+}
+#[derive(Template)]
+#[template(path = "admin/report-queue.html")]
+struct ReportQueueTemplate {
+    viewer_role: Role,
+    reports: Vec<OpenReport>,
+}
+/// Responds with the remaining queue, reports about the same content are resolved together.
+/// Fires `reportResolved` so the audit log refreshes itself.
+async fn resolve_report(
+    RequireRole(user, _): RequireRole<Moderator>,
+    Path(report_id): Path<i32>,
+    Extension(connection_pool): Extension<SqlitePool>,
+    Form(form): Form<OutcomeForm>,
+) -> AppResult {
+    // Another moderator may have been faster, then the queue is just brought up to date
+    if let Some(report) = db::reports::get_open_report(&connection_pool, report_id).await? {
+        if form.outcome == Outcome::Ban && report.author_role >= user.role {
+            return Err(AppError::Forbidden);
+        }
+        db::reports::resolve(&connection_pool, user.id, &report, form.outcome).await?;
+    }
+
+    let template = ReportQueueTemplate {
+        viewer_role: user.role,
+        reports: db::reports::open_reports(&connection_pool).await?,
+    };
+
+    let mut headers = HeaderMap::new();
+    headers.insert("HX-Trigger", "reportResolved".parse().unwrap());
+
+    Ok((headers, Html(template.to_string())).into_response())
+}
+
+#[derive(Template)]
+#[template(path = "admin/audit-log.html")]
+struct AuditLogTemplate {
+    audit_log: Vec<AuditEntry>,
+}
+async fn get_audit_log(Extension(connection_pool): Extension<SqlitePool>) -> AppResult {
+    let audit_log = db::reports::audit_log(&connection_pool).await?;
+
+    Ok(Html(AuditLogTemplate { audit_log }.to_string()).into_response())
+}
@@ -0,0 +1,314 @@
+use askama::Template;
+use axum::{
+    extract::Path,
+    response::{Html, IntoResponse},
+    routing::{delete, get, post, put},
+    Extension, Form, Router,
+};
+use axum_extra::extract::cookie::CookieJar;
+use serde::Deserialize;
+use sqlx::SqlitePool;
+
+use super::{
+    auth::{confirm_password, EMAIL_TAKEN},
+    two_factor::TwoFactorSection,
+    verification::send_verification_mail,
+};
+use crate::{
+    csrf::CsrfToken,
+    db::{self, Session},
+    error::{AppError, AppResult},
+    extractors::CurrentUser,
+    helpers::get_session_token,
+    mail::SharedMailer,
+    password, validation,
+};
+
+pub fn setup_settings_router() -> Router {
+    Router::new()
+        .route("/settings", get(settings_page))
+        .route("/settings/name", put(update_name))
+        .route("/settings/bio", put(update_bio))
+        .route("/settings/email", put(update_email))
+        .route("/settings/password", put(update_password))
+        .route("/settings/sessions", get(sessions_page))
+        .route("/settings/sessions/:session_id", delete(revoke_session))
+        .route(
+            "/settings/sessions/revoke-others",
+            post(revoke_other_sessions),
+        )
+}
+
+/// Shown under a settings form after it was submitted
+pub enum Feedback {
+    None,
+    Saved,
+    Invalid(String),
+}
+impl From<String> for Feedback {
+    fn from(message: String) -> Self {
+        Feedback::Invalid(message)
+    }
+}
+
+#[derive(Template)]
+#[template(path = "settings/index.html")]
+struct SettingsTemplate<'a> {
+    csrf_token: String,
+    user_name: Option<&'a str>,
+    name: &'a str,
+    name_feedback: Feedback,
+    bio: &'a str,
+    bio_feedback: Feedback,
+    email: &'a str,
+    email_verified: bool,
+    email_feedback: Feedback,
+    password_feedback: Feedback,
+    two_factor: TwoFactorSection,
+    /// Links to the admin dashboard
+    is_moderator: bool,
+}
+async fn settings_page(
+    CsrfToken(csrf_token): CsrfToken,
+    CurrentUser(user): CurrentUser,
+    Extension(connection_pool): Extension<SqlitePool>,
+) -> AppResult {
+    let profile = db::get_profile(&connection_pool, None, user.id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    let template = SettingsTemplate {
+        csrf_token,
+        user_name: Some(&user.name),
+        name: &user.name,
+        name_feedback: Feedback::None,
+        bio: &profile.bio,
+        bio_feedback: Feedback::None,
+        email: &user.email,
+        email_verified: user.email_verified(),
+        email_feedback: Feedback::None,
+        password_feedback: Feedback::None,
+        two_factor: TwoFactorSection::load(&connection_pool, user.id).await?,
+        is_moderator: user.is_moderator(),
+    };
+
+    Ok(Html(template.to_string()).into_response())
+}
+
+#[derive(Deserialize)]
+struct NameForm {
+    name: String,
+}
+#[derive(Template)]
+#[template(path = "settings/name-form.html")]
+struct NameFormTemplate<'a> {
+    name: &'a str,
+    name_feedback: Feedback,
+}
+/// Every settings form responds with itself, showing what went wrong or that it was saved
+async fn update_name(
+    CurrentUser(user): CurrentUser,
+    Extension(connection_pool): Extension<SqlitePool>,
+    Form(form): Form<NameForm>,
+) -> AppResult {
+    let template = match validation::name(&form.name) {
+        Ok(name) => {
+            db::update_name(&connection_pool, user.id, name).await?;
+            NameFormTemplate {
+                name,
+                name_feedback: Feedback::Saved,
+            }
+        }
+        Err(message) => NameFormTemplate {
+            name: &form.name,
+            name_feedback: message.into(),
+        },
+    };
+
+    Ok(Html(template.to_string()).into_response())
+}
+
+#[derive(Deserialize)]
+struct BioForm {
+    bio: String,
+}
+#[derive(Template)]
+#[template(path = "settings/bio-form.html")]
+struct BioFormTemplate<'a> {
+    bio: &'a str,
+    bio_feedback: Feedback,
+}
+async fn update_bio(
+    CurrentUser(user): CurrentUser,
+    Extension(connection_pool): Extension<SqlitePool>,
+    Form(form): Form<BioForm>,
+) -> AppResult {
+    let template = match validation::bio(&form.bio) {
+        Ok(bio) => {
+            db::update_bio(&connection_pool, user.id, bio).await?;
+            BioFormTemplate {
+                bio,
+                bio_feedback: Feedback::Saved,
+            }
+        }
+        Err(message) => BioFormTemplate {
+            bio: &form.bio,
+            bio_feedback: message.into(),
+        },
+    };
+
+    Ok(Html(template.to_string()).into_response())
+}
+
+#[derive(Deserialize)]
+struct EmailForm {
+    email: String,
+    current_password: String,
+}
+#[derive(Template)]
+#[template(path = "settings/email-form.html")]
+struct EmailFormTemplate<'a> {
+    email: &'a str,
+    email_verified: bool,
+    email_feedback: Feedback,
+}
+/// Needs the password, an open session alone could take over the account through a password reset.
+/// A new address has to be confirmed again through the mailed link.
+async fn update_email(
+    CurrentUser(user): CurrentUser,
+    Extension(connection_pool): Extension<SqlitePool>,
+    Extension(mailer): Extension<SharedMailer>,
+    Form(form): Form<EmailForm>,
+) -> AppResult {
+    let email = match validation::email(&form.email) {
+        Ok(email) => email,
+        Err(message) => {
+            let template = EmailFormTemplate {
+                email: &form.email,
+                email_verified: user.email_verified(),
+                email_feedback: message.into(),
+            };
+            return Ok(Html(template.to_string()).into_response());
+        }
+    };
+
+    // Addresses from before emails were normalized may still hold capitals
+    let (email_verified, email_feedback) = if email == user.email.to_lowercase() {
+        (user.email_verified(), Feedback::Saved)
+    } else if !confirm_password(&connection_pool, &user, &form.current_password).await? {
+        (
+            user.email_verified(),
+            Feedback::Invalid("Current password is incorrect".to_owned()),
+        )
+    } else if db::check_email_exists(&connection_pool, &email).await?
+        || !db::update_email(&connection_pool, user.id, &email).await?
+    {
+        (
+            user.email_verified(),
+            Feedback::Invalid(EMAIL_TAKEN.to_owned()),
+        )
+    } else {
+        send_verification_mail(mailer, user.id, email.clone());
+        (false, Feedback::Saved)
+    };
+
+    let template = EmailFormTemplate {
+        email: &email,
+        email_verified,
+        email_feedback,
+    };
+    Ok(Html(template.to_string()).into_response())
+}
+
+#[derive(Deserialize)]
+struct PasswordForm {
+    current_password: String,
+    new_password: String,
+}
+#[derive(Template)]
+#[template(path = "settings/password-form.html")]
+struct PasswordFormTemplate {
+    password_feedback: Feedback,
+}
+/// Changing the password logs out every other session
+async fn update_password(
+    jar: CookieJar,
+    CurrentUser(user): CurrentUser,
+    Extension(connection_pool): Extension<SqlitePool>,
+    Form(form): Form<PasswordForm>,
+) -> AppResult {
+    let password_feedback =
+        if confirm_password(&connection_pool, &user, &form.current_password).await? {
+            match validation::new_password(&form.new_password) {
+                Ok(new_password) => {
+                    let password_hash = password::hash(new_password).await?;
+                    db::update_password_hash(&connection_pool, user.id, &password_hash).await?;
+
+                    let session_token = get_session_token(&jar).unwrap_or_default();
+                    db::delete_other_sessions(&connection_pool, user.id, &session_token).await?;
+
+                    Feedback::Saved
+                }
+                Err(message) => message.into(),
+            }
+        } else {
+            Feedback::Invalid("Current password is incorrect".to_owned())
+        };
+
+    Ok(Html(PasswordFormTemplate { password_feedback }.to_string()).into_response())
+}
+
+#[derive(Template)]
+#[template(path = "sessions.html")]
+struct SessionsTemplate<'a> {
+    csrf_token: String,
+    user_name: Option<&'a str>,
+    sessions: Vec<Session>,
+}
+async fn sessions_page(
+    CsrfToken(csrf_token): CsrfToken,
+    jar: CookieJar,
+    CurrentUser(user): CurrentUser,
+    Extension(connection_pool): Extension<SqlitePool>,
+) -> AppResult {
+    let session_token = get_session_token(&jar).unwrap_or_default();
+
+    let sessions = db::get_user_sessions(&connection_pool, user.id, &session_token).await?;
+
+    let template = SessionsTemplate {
+        csrf_token,
+        user_name: Some(&user.name),
+        sessions,
+    };
+
+    Ok(Html(template.to_string()).into_response())
+}
+
+/// Responds with an empty body so that htmx removes the session row
+async fn revoke_session(
+    CurrentUser(user): CurrentUser,
+    Path(session_id): Path<i32>,
+    Extension(connection_pool): Extension<SqlitePool>,
+) -> AppResult {
+    db::delete_user_session(&connection_pool, user.id, session_id).await?;
+
+    Ok(Html("").into_response())
+}
+
+#[derive(Template)]
+#[template(path = "sessions-list.html")]
+struct SessionsListTemplate {
+    sessions: Vec<Session>,
+}
+async fn revoke_other_sessions(
+    jar: CookieJar,
+    CurrentUser(user): CurrentUser,
+    Extension(connection_pool): Extension<SqlitePool>,
+) -> AppResult {
+    let session_token = get_session_token(&jar).unwrap_or_default();
+
+    db::delete_other_sessions(&connection_pool, user.id, &session_token).await?;
+    let sessions = db::get_user_sessions(&connection_pool, user.id, &session_token).await?;
+
+    Ok(Html(SessionsListTemplate { sessions }.to_string()).into_response())
+}
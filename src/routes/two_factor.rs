@@ -0,0 +1,259 @@
+use askama::Template;
+use axum::{
+    extract::ConnectInfo,
+    response::{Html, IntoResponse},
+    routing::post,
+    Extension, Form, Router,
+};
+use axum_extra::extract::cookie::CookieJar;
+use hyper::HeaderMap;
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use std::net::SocketAddr;
+
+use super::auth::{confirm_password, count_attempt, start_session, LoginFormTemplate};
+use crate::{
+    db::{self, rate_limits, two_factor},
+    error::{AppError, AppResult},
+    extractors::CurrentUser,
+    helpers::{get_login_challenge_token, login_challenge_cookie, remove_login_challenge_cookie},
+    totp,
+};
+
+pub fn setup_two_factor_router() -> Router {
+    Router::new()
+        .route("/login/two-factor", post(complete_login))
+        .route("/settings/two-factor/setup", post(start_enrolment))
+        .route("/settings/two-factor/enable", post(enable))
+        .route("/settings/two-factor/disable", post(disable))
+}
+
+/// What the two-factor section of the settings page shows
+pub enum TwoFactorSection {
+    Off,
+    Enrolling {
+        secret: String,
+        otpauth_uri: String,
+        qr_svg: String,
+        error: Option<&'static str>,
+    },
+    /// Right after enabling, the only time the codes are shown
+    RecoveryCodes(Vec<String>),
+    On {
+        recovery_codes_left: i64,
+        error: Option<&'static str>,
+    },
+}
+
+impl TwoFactorSection {
+    /// How the section looks when the settings page is opened
+    pub async fn load(connection_pool: &SqlitePool, user_id: i32) -> AppResult<Self> {
+        if !two_factor::get_state(connection_pool, user_id)
+            .await?
+            .enabled
+        {
+            return Ok(TwoFactorSection::Off);
+        }
+
+        Ok(TwoFactorSection::On {
+            recovery_codes_left: two_factor::remaining_recovery_codes(connection_pool, user_id)
+                .await?,
+            error: None,
+        })
+    }
+
+    fn enrolling(secret: String, account: &str, error: Option<&'static str>) -> AppResult<Self> {
+        let otpauth_uri = totp::otpauth_uri(&secret, account)?;
+        let qr_svg = totp::qr_svg(&otpauth_uri)?;
+
+        Ok(TwoFactorSection::Enrolling {
+            secret,
+            otpauth_uri,
+            qr_svg,
+            error,
+        })
+    }
+}
+
+#[derive(Template)]
+#[template(path = "settings/two-factor.html")]
+struct TwoFactorSectionTemplate {
+    two_factor: TwoFactorSection,
+}
+impl TwoFactorSectionTemplate {
+    fn render(two_factor: TwoFactorSection) -> AppResult {
+        Ok(Html(TwoFactorSectionTemplate { two_factor }.to_string()).into_response())
+    }
+}
+
+/// New secret shown as a QR code, not used for logins until a code from it is entered
+async fn start_enrolment(
+    CurrentUser(user): CurrentUser,
+    Extension(connection_pool): Extension<SqlitePool>,
+) -> AppResult {
+    if two_factor::get_state(&connection_pool, user.id)
+        .await?
+        .enabled
+    {
+        return Err(AppError::BadRequest(
+            "Two-factor authentication is already on".to_owned(),
+        ));
+    }
+
+    let secret = totp::generate_secret();
+    two_factor::start_enrolment(&connection_pool, user.id, &secret).await?;
+
+    TwoFactorSectionTemplate::render(TwoFactorSection::enrolling(secret, &user.email, None)?)
+}
+
+#[derive(Deserialize)]
+struct CodeForm {
+    code: String,
+}
+async fn enable(
+    CurrentUser(user): CurrentUser,
+    Extension(connection_pool): Extension<SqlitePool>,
+    Form(form): Form<CodeForm>,
+) -> AppResult {
+    let state = two_factor::get_state(&connection_pool, user.id).await?;
+    let Some(secret) = state.secret.filter(|_| !state.enabled) else {
+        return Err(AppError::BadRequest(
+            "Start the setup of two-factor authentication first".to_owned(),
+        ));
+    };
+
+    let Some(step) = totp::matching_step(&secret, &user.email, form.code.trim())? else {
+        return TwoFactorSectionTemplate::render(TwoFactorSection::enrolling(
+            secret,
+            &user.email,
+            Some("That code doesn't match, try the current one"),
+        )?);
+    };
+
+    let recovery_codes = totp::generate_recovery_codes();
+    two_factor::enable(&connection_pool, user.id, step, &recovery_codes).await?;
+
+    TwoFactorSectionTemplate::render(TwoFactorSection::RecoveryCodes(recovery_codes))
+}
+
+#[derive(Deserialize)]
+struct DisableForm {
+    password: String,
+}
+/// Needs the password, so a forgotten open session isn't enough to turn it off
+async fn disable(
+    CurrentUser(user): CurrentUser,
+    Extension(connection_pool): Extension<SqlitePool>,
+    Form(form): Form<DisableForm>,
+) -> AppResult {
+    if !confirm_password(&connection_pool, &user, &form.password).await? {
+        return TwoFactorSectionTemplate::render(TwoFactorSection::On {
+            recovery_codes_left: two_factor::remaining_recovery_codes(&connection_pool, user.id)
+                .await?,
+            error: Some("Password is incorrect"),
+        });
+    }
+
+    two_factor::disable(&connection_pool, user.id).await?;
+
+    TwoFactorSectionTemplate::render(TwoFactorSection::Off)
+}
+
+#[derive(Template)]
+#[template(path = "login-form/two-factor.html")]
+struct CodeEntryTemplate {
+    code_failed: bool,
+}
+
+/// The password was right, hold off the session until a code is entered too
+pub async fn start_two_factor_challenge(
+    connection_pool: &SqlitePool,
+    jar: CookieJar,
+    user_id: i32,
+    remember: bool,
+) -> AppResult {
+    let token = two_factor::create_challenge(connection_pool, user_id, remember).await?;
+
+    let template = CodeEntryTemplate { code_failed: false };
+    Ok((
+        jar.add(login_challenge_cookie(token)),
+        Html(template.to_string()),
+    )
+        .into_response())
+}
+
+/// Takes an authenticator code or a recovery code.
+/// A missing, expired or used up challenge goes back to the login form,
+/// as does one left over from before two-factor login was turned off.
+async fn complete_login(
+    Extension(connection_pool): Extension<SqlitePool>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    jar: CookieJar,
+    Form(form): Form<CodeForm>,
+) -> AppResult {
+    let challenge = match get_login_challenge_token(&jar) {
+        Some(token) => two_factor::get_challenge(&connection_pool, &token).await?,
+        None => None,
+    };
+    let start_over = |jar| {
+        let template = LoginFormTemplate {
+            email: String::new(),
+            login_failed: false,
+        };
+        let jar = remove_login_challenge_cookie(jar);
+        Ok((jar, Html(template.to_string())).into_response())
+    };
+    let Some(challenge) = challenge else {
+        return start_over(jar);
+    };
+
+    // Two-factor login was turned off while the code was pending
+    let state = two_factor::get_state(&connection_pool, challenge.user_id).await?;
+    let (true, Some(secret)) = (state.enabled, state.secret) else {
+        two_factor::delete_challenge(&connection_pool, challenge.id).await?;
+        return start_over(jar);
+    };
+
+    // Counted per account so that starting over with the password doesn't reset it
+    let key = format!("two-factor:{}", challenge.user_id);
+    count_attempt(
+        &connection_pool,
+        &[(&key, &rate_limits::LOGIN_ACCOUNT_LIMIT)],
+    )
+    .await?;
+
+    let user = db::get_user_by_id(&connection_pool, challenge.user_id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    let code = form.code.trim();
+    let accepted = match totp::matching_step(&secret, &user.email, code)? {
+        Some(step) => two_factor::use_step(&connection_pool, user.id, step).await?,
+        None => {
+            let recovery_code = totp::normalize_recovery_code(code);
+            two_factor::use_recovery_code(&connection_pool, user.id, &recovery_code).await?
+        }
+    };
+
+    if !accepted {
+        two_factor::record_challenge_failure(&connection_pool, challenge.id).await?;
+
+        let template = CodeEntryTemplate { code_failed: true };
+        return Ok(Html(template.to_string()).into_response());
+    }
+
+    rate_limits::clear(&connection_pool, &key).await?;
+    two_factor::delete_challenge(&connection_pool, challenge.id).await?;
+    let jar = remove_login_challenge_cookie(jar);
+
+    start_session(
+        &connection_pool,
+        jar,
+        &headers,
+        address,
+        user.id,
+        challenge.remember,
+    )
+    .await
+}
@@ -0,0 +1,134 @@
+use askama::Template;
+use axum::{
+    extract::Path,
+    response::{Html, IntoResponse},
+    routing::{get, post},
+    Extension, Router,
+};
+use sqlx::SqlitePool;
+
+use super::posts::author_feed_url;
+use crate::{
+    csrf::CsrfToken,
+    db::{
+        self,
+        posts::{Cursor, Feed, Post},
+        Profile,
+    },
+    error::{AppError, AppResult},
+    extractors::{CurrentUser, MaybeUser},
+};
+
+pub fn setup_users_router() -> Router {
+    Router::new()
+        .route("/users/:user_id", get(get_profile))
+        .route(
+            "/users/:user_id/follow",
+            post(follow_user).delete(unfollow_user),
+        )
+}
+
+#[derive(Template)]
+#[template(path = "profile.html")]
+struct ProfileTemplate<'a> {
+    csrf_token: String,
+    user_name: Option<&'a str>,
+    profile: Profile,
+    /// Whether the profile belongs to the current user
+    is_self: bool,
+    // posts related
+    posts: Vec<Post>,
+    next_cursor: Option<Cursor>,
+    feed_url: String,
+}
+async fn get_profile(
+    CsrfToken(csrf_token): CsrfToken,
+    MaybeUser(user): MaybeUser,
+    Path(user_id): Path<i32>,
+    Extension(connection_pool): Extension<SqlitePool>,
+) -> AppResult {
+    let viewer_id = user.as_ref().map(|u| u.id);
+
+    let profile = db::get_profile(&connection_pool, viewer_id, user_id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    let page = db::posts::get_page(
+        &connection_pool,
+        viewer_id,
+        user.as_ref().is_some_and(|u| u.is_moderator()),
+        Feed::Author(user_id),
+        None,
+        db::posts::DEFAULT_PAGE_SIZE,
+    )
+    .await?;
+
+    let user_name = user.map(|u| u.name);
+    let template = ProfileTemplate {
+        csrf_token,
+        user_name: user_name.as_deref(),
+        is_self: viewer_id == Some(profile.id),
+        profile,
+        posts: page.posts,
+        next_cursor: page.next_cursor,
+        feed_url: author_feed_url(user_id),
+    };
+
+    Ok(Html(template.to_string()).into_response())
+}
+
+#[derive(Template)]
+#[template(path = "follow-button.html")]
+struct FollowButtonTemplate {
+    author_id: i32,
+    following: bool,
+    /// Responses replace every button for the author on the page out of band,
+    /// the clicked one included as it swaps nothing itself
+    swap_oob: bool,
+}
+/// Only existing users other than the current one can be followed
+async fn ensure_followable(
+    connection_pool: &SqlitePool,
+    user_id: i32,
+    followee_id: i32,
+) -> AppResult<()> {
+    if user_id == followee_id {
+        return Err(AppError::BadRequest("You can't follow yourself".to_owned()));
+    }
+    if db::get_user_by_id(connection_pool, followee_id)
+        .await?
+        .is_none()
+    {
+        return Err(AppError::NotFound);
+    }
+    Ok(())
+}
+async fn follow_user(
+    CurrentUser(user): CurrentUser,
+    Path(followee_id): Path<i32>,
+    Extension(connection_pool): Extension<SqlitePool>,
+) -> AppResult {
+    ensure_followable(&connection_pool, user.id, followee_id).await?;
+
+    db::follows::follow(&connection_pool, user.id, followee_id).await?;
+
+    let template = FollowButtonTemplate {
+        author_id: followee_id,
+        following: true,
+        swap_oob: true,
+    };
+    Ok(Html(template.to_string()).into_response())
+}
+async fn unfollow_user(
+    CurrentUser(user): CurrentUser,
+    Path(followee_id): Path<i32>,
+    Extension(connection_pool): Extension<SqlitePool>,
+) -> AppResult {
+    db::follows::unfollow(&connection_pool, user.id, followee_id).await?;
+
+    let template = FollowButtonTemplate {
+        author_id: followee_id,
+        following: false,
+        swap_oob: true,
+    };
+    Ok(Html(template.to_string()).into_response())
+}
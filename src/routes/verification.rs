@@ -0,0 +1,124 @@
+use askama::Template;
+use axum::{
+    extract::Query,
+    response::{Html, IntoResponse},
+    routing::{get, post},
+    Extension, Router,
+};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use time::OffsetDateTime;
+
+use super::auth::count_attempt;
+use crate::{
+    csrf::CsrfToken,
+    db::{self, rate_limits},
+    error::{AppError, AppResult},
+    extractors::CurrentUser,
+    mail::{app_url, Email, SharedMailer},
+    signing,
+};
+
+/// How long a verification link stays valid, in seconds
+const VERIFICATION_LINK_LIFETIME: i64 = 24 * 60 * 60;
+
+pub fn setup_verification_router() -> Router {
+    Router::new()
+        .route("/verify-email", get(verify_email))
+        .route("/verify-email/resend", post(resend_verification))
+}
+
+/// The signature covers the address, so links die with an email change
+fn signed_message(user_id: i32, email: &str, expires: i64) -> String {
+    format!("verify-email:{user_id}:{email}:{expires}")
+}
+
+/// Mail a signed verification link in the background
+pub fn send_verification_mail(mailer: SharedMailer, user_id: i32, email: String) {
+    let expires = OffsetDateTime::now_utc().unix_timestamp() + VERIFICATION_LINK_LIFETIME;
+    let signature = signing::sign(&signed_message(user_id, &email, expires));
+    let link = app_url(&format!(
+        "/verify-email?user={user_id}&expires={expires}&signature={signature}"
+    ));
+
+    let email = Email {
+        to: email,
+        subject: "Confirm your email address".to_owned(),
+        body: format!(
+            "Open this link within {} hours to confirm your email address:\n{link}\n\n\
+             Until then you can't post or comment.",
+            VERIFICATION_LINK_LIFETIME / 60 / 60
+        ),
+    };
+    tokio::spawn(async move {
+        if let Err(error) = mailer.send(email).await {
+            tracing::error!("Failed to send verification mail: {error:#}");
+        }
+    });
+}
+
+#[derive(Deserialize)]
+struct VerifyQuery {
+    user: i32,
+    expires: i64,
+    signature: String,
+}
+#[derive(Template)]
+#[template(path = "verify-email.html")]
+struct VerifyEmailTemplate {
+    csrf_token: String,
+    verified: bool,
+}
+async fn verify_email(
+    CsrfToken(csrf_token): CsrfToken,
+    Query(query): Query<VerifyQuery>,
+    Extension(connection_pool): Extension<SqlitePool>,
+) -> AppResult {
+    let user = db::get_user_by_id(&connection_pool, query.user).await?;
+
+    let verified = match user {
+        Some(user) => {
+            let message = signed_message(user.id, &user.email, query.expires);
+            let valid = query.expires > OffsetDateTime::now_utc().unix_timestamp()
+                && signing::verify(&message, &query.signature);
+            if valid {
+                db::mark_email_verified(&connection_pool, user.id, &user.email).await?;
+            }
+            valid || user.email_verified()
+        }
+        None => false,
+    };
+
+    let template = VerifyEmailTemplate {
+        csrf_token,
+        verified,
+    };
+    Ok(Html(template.to_string()).into_response())
+}
+
+#[derive(Template)]
+#[template(path = "verification-sent.html")]
+struct VerificationSentTemplate;
+/// Rate limited per user, the button is replaced with a confirmation
+async fn resend_verification(
+    CurrentUser(user): CurrentUser,
+    Extension(connection_pool): Extension<SqlitePool>,
+    Extension(mailer): Extension<SharedMailer>,
+) -> AppResult {
+    if user.email_verified() {
+        return Err(AppError::BadRequest(
+            "Your email address is already confirmed".to_owned(),
+        ));
+    }
+
+    let key = format!("verification-mail:{}", user.id);
+    count_attempt(
+        &connection_pool,
+        &[(&key, &rate_limits::VERIFICATION_MAIL_LIMIT)],
+    )
+    .await?;
+
+    send_verification_mail(mailer, user.id, user.email);
+
+    Ok(Html(VerificationSentTemplate.to_string()).into_response())
+}
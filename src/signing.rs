@@ -0,0 +1,38 @@
+use std::sync::OnceLock;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{helpers::generate_token, password::constant_time_eq};
+
+/// Key for signing links, from `APP_SECRET`.
+/// Without it a random one is used, and links stop working on restart.
+fn secret() -> &'static [u8] {
+    static SECRET: OnceLock<String> = OnceLock::new();
+
+    SECRET
+        .get_or_init(|| {
+            std::env::var("APP_SECRET").unwrap_or_else(|_| {
+                tracing::warn!("APP_SECRET is not set, signed links won't survive a restart");
+                generate_token()
+            })
+        })
+        .as_bytes()
+}
+
+/// Hex encoded HMAC-SHA256 of `message`
+pub fn sign(message: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret()).expect("HMAC accepts keys of any length");
+    mac.update(message.as_bytes());
+
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+pub fn verify(message: &str, signature: &str) -> bool {
+    constant_time_eq(sign(message).as_bytes(), signature.as_bytes())
+}
@@ -0,0 +1,62 @@
+use std::fmt;
+
+use time::{format_description::well_known::Rfc3339, macros::format_description, OffsetDateTime};
+
+/// Unix timestamp in seconds, as stored in the database
+#[derive(sqlx::Type, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[sqlx(transparent)]
+pub struct Timestamp(pub i64);
+
+impl Timestamp {
+    /// "5 minutes ago" style label, falls back to the date for old timestamps
+    pub fn relative(&self) -> String {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let seconds = now - self.0;
+
+        let (amount, unit) = match seconds {
+            ..=59 => return "just now".to_owned(),
+            60..=3599 => (seconds / 60, "minute"),
+            3600..=86399 => (seconds / 3600, "hour"),
+            86400..=2_591_999 => (seconds / 86400, "day"),
+            _ => return self.date(),
+        };
+
+        if amount == 1 {
+            format!("1 {unit} ago")
+        } else {
+            format!("{amount} {unit}s ago")
+        }
+    }
+
+    /// For the `datetime` attribute of `<time>`
+    pub fn rfc3339(&self) -> String {
+        OffsetDateTime::from_unix_timestamp(self.0)
+            .ok()
+            .and_then(|date_time| date_time.format(&Rfc3339).ok())
+            .unwrap_or_default()
+    }
+
+    fn date(&self) -> String {
+        let format = format_description!("[year]-[month]-[day]");
+
+        OffsetDateTime::from_unix_timestamp(self.0)
+            .ok()
+            .and_then(|date_time| date_time.format(format).ok())
+            .unwrap_or_else(|| self.0.to_string())
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let format = format_description!("[year]-[month]-[day] [hour]:[minute] UTC");
+
+        let formatted = OffsetDateTime::from_unix_timestamp(self.0)
+            .ok()
+            .and_then(|date_time| date_time.format(format).ok());
+
+        match formatted {
+            Some(formatted) => f.write_str(&formatted),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use anyhow::{anyhow, Result};
+use qrcode::{render::svg, QrCode};
+use rand::{rngs::OsRng, RngCore};
+use time::OffsetDateTime;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::password::constant_time_eq;
+
+/// Shown as the account's issuer in authenticator apps
+const ISSUER: &str = "axum-htmx";
+/// RFC 6238 defaults, which is what every authenticator app expects
+const DIGITS: usize = 6;
+const STEP: u64 = 30;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// New random secret, base32 encoded
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 20];
+    OsRng.fill_bytes(&mut bytes);
+
+    Secret::Raw(bytes.to_vec()).to_encoded().to_string()
+}
+
+fn totp(secret: &str, account: &str) -> Result<TOTP> {
+    let secret = Secret::Encoded(secret.to_owned())
+        .to_bytes()
+        .map_err(|error| anyhow!("Invalid TOTP secret: {error:?}"))?;
+
+    // `:` separates issuer and account in the URI
+    let account = account.replace(':', "_");
+
+    Ok(TOTP::new(
+        Algorithm::SHA1,
+        DIGITS,
+        1,
+        STEP,
+        secret,
+        Some(ISSUER.to_owned()),
+        account,
+    )?)
+}
+
+/// `otpauth://` URI which authenticator apps enrol from
+pub fn otpauth_uri(secret: &str, account: &str) -> Result<String> {
+    Ok(totp(secret, account)?.get_url())
+}
+
+/// `data` as an `<svg>` element, for embedding into a page
+pub fn qr_svg(data: &str) -> Result<String> {
+    let svg = QrCode::new(data)?
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .build();
+
+    // Drop the XML declaration
+    let start = svg.find("<svg").unwrap_or_default();
+    Ok(svg[start..].to_owned())
+}
+
+/// Time step `code` belongs to, if it is valid right now.
+/// Codes from one step before or after are accepted for clock drift.
+pub fn matching_step(secret: &str, account: &str, code: &str) -> Result<Option<i64>> {
+    let totp = totp(secret, account)?;
+    let now = OffsetDateTime::now_utc().unix_timestamp() as u64;
+
+    let current_step = now / STEP;
+    let step = [current_step - 1, current_step, current_step + 1]
+        .into_iter()
+        .find(|step| constant_time_eq(totp.generate(step * STEP).as_bytes(), code.as_bytes()));
+
+    Ok(step.map(|step| step as i64))
+}
+
+/// Fresh single-use codes for when the authenticator is lost, formatted `xxxxx-xxxxx-xxxxx-xxxxx`.
+/// 80 random bits each, so the fast hash they are stored with can't be brute forced.
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let mut bytes = [0u8; 10];
+            OsRng.fill_bytes(&mut bytes);
+            let hex: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+
+            format!(
+                "{}-{}-{}-{}",
+                &hex[..5],
+                &hex[5..10],
+                &hex[10..15],
+                &hex[15..]
+            )
+        })
+        .collect()
+}
+
+/// How users may type a recovery code, compared after this
+pub fn normalize_recovery_code(code: &str) -> String {
+    code.trim().to_lowercase()
+}
@@ -0,0 +1,91 @@
+//! Rules for user supplied account fields.
+//! Errors are messages meant to be shown next to the offending input.
+
+pub const MAX_NAME_LENGTH: usize = 50;
+pub const MAX_BIO_LENGTH: usize = 500;
+pub const MAX_EMAIL_LENGTH: usize = 254;
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+pub const MAX_PASSWORD_LENGTH: usize = 128;
+pub const MAX_REPORT_REASON_LENGTH: usize = 500;
+
+/// Trimmed display name
+pub fn name(name: &str) -> Result<&str, String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("Name can't be empty".to_owned());
+    }
+    if name.chars().count() > MAX_NAME_LENGTH {
+        return Err(format!(
+            "Name can't be longer than {MAX_NAME_LENGTH} characters"
+        ));
+    }
+    Ok(name)
+}
+
+/// Trimmed bio, may be empty
+pub fn bio(bio: &str) -> Result<&str, String> {
+    let bio = bio.trim();
+    if bio.chars().count() > MAX_BIO_LENGTH {
+        return Err(format!(
+            "Bio can't be longer than {MAX_BIO_LENGTH} characters"
+        ));
+    }
+    Ok(bio)
+}
+
+/// Trimmed reason given when reporting a post or comment
+pub fn report_reason(reason: &str) -> Result<&str, String> {
+    let reason = reason.trim();
+    if reason.is_empty() {
+        return Err("Tell us what is wrong with it".to_owned());
+    }
+    if reason.chars().count() > MAX_REPORT_REASON_LENGTH {
+        return Err(format!(
+            "Reason can't be longer than {MAX_REPORT_REASON_LENGTH} characters"
+        ));
+    }
+    Ok(reason)
+}
+
+/// Trimmed and lowercased email, so that one address can't be registered twice in different case.
+/// Only checks the overall shape, `local@domain.tld`, whether it actually receives mail is another matter.
+pub fn email(email: &str) -> Result<String, String> {
+    let email = email.trim().to_lowercase();
+    let invalid = || Err("Enter a valid email address".to_owned());
+
+    if email.len() > MAX_EMAIL_LENGTH || email.chars().any(char::is_whitespace) {
+        return invalid();
+    }
+    let Some((local, domain)) = email.split_once('@') else {
+        return invalid();
+    };
+    let domain_valid = !domain.contains('@')
+        && domain.contains('.')
+        && domain.split('.').all(|label| !label.is_empty());
+    if local.is_empty() || !domain_valid {
+        return invalid();
+    }
+    Ok(email)
+}
+
+/// A new password, unlike the other fields it is never trimmed.
+/// Besides the length it has to mix letters with digits or symbols.
+pub fn new_password(password: &str) -> Result<&str, String> {
+    let length = password.chars().count();
+    if length < MIN_PASSWORD_LENGTH {
+        return Err(format!(
+            "Password must be at least {MIN_PASSWORD_LENGTH} characters"
+        ));
+    }
+    if length > MAX_PASSWORD_LENGTH {
+        return Err(format!(
+            "Password can't be longer than {MAX_PASSWORD_LENGTH} characters"
+        ));
+    }
+    let has_letter = password.chars().any(char::is_alphabetic);
+    let has_other = password.chars().any(|c| !c.is_alphabetic());
+    if !has_letter || !has_other {
+        return Err("Password must mix letters with digits or symbols".to_owned());
+    }
+    Ok(password)
+}